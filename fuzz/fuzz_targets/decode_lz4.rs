@@ -0,0 +1,8 @@
+#![no_main]
+
+use hpk::compress::Lz4Block;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    hpk::fuzzing::decode::<Lz4Block>(data);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use hpk::compress::Zstd;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    hpk::fuzzing::decode::<Zstd>(data);
+});
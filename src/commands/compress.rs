@@ -0,0 +1,71 @@
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+
+use clap::builder::PathBufValueParser;
+use clap::{arg, ArgMatches, Command};
+
+use super::progress::Reporter;
+use crate::CliResult;
+
+const ABOUT_HELP: &str = "Compress a whole hpk archive or the data of an archive member.
+
+The output can be the input file itself. It's replaced once it's completely written.";
+
+pub fn cmd() -> Command {
+    fn input_parser(value: &str) -> Result<PathBuf, String> {
+        let path = Path::new(value);
+        match path.metadata() {
+            Ok(ref md) if md.is_file() => Ok(path.to_path_buf()),
+            Ok(_) | Err(_) => Err(String::from("Not a valid file")),
+        }
+    }
+
+    Command::new("compress")
+        .about("Compress a whole hpk archive or the data of an archive member")
+        .long_about(ABOUT_HELP)
+        .display_order(12)
+        .arg(arg!(--lz4 "Sets LZ4 as encoder").conflicts_with("zstd"))
+        .arg(arg!(--zstd "Sets ZSTD as encoder"))
+        .arg(
+            arg!(chunk_size: --"chunk-size" <SIZE> "Default chunk size: 32768")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .next_line_help(true),
+        )
+        .arg(arg!(<input> "hpk archive or member data").value_parser(input_parser))
+        .arg(arg!(<output> "compressed output file").value_parser(PathBufValueParser::new()))
+}
+
+pub fn execute(matches: &ArgMatches) -> CliResult {
+    let input = matches.get_one::<PathBuf>("input").expect("required arg");
+    let output = matches.get_one::<PathBuf>("output").expect("required arg");
+
+    let mut options = hpk::CompressOptions::default();
+    if matches.get_flag("lz4") {
+        options.use_lz4();
+    }
+    if matches.get_flag("zstd") {
+        options.use_zstd();
+    }
+    if let Some(chunk_size) = matches.get_one::<u32>("chunk_size") {
+        options.with_chunk_size(*chunk_size);
+    }
+
+    let total_bytes = input.metadata().map_err(hpk::HpkError::Io)?.len();
+    let reporter = Reporter::new(true);
+    let callback = reporter.callback();
+    let report = |bytes| {
+        callback(&hpk::Progress {
+            total_files: 1,
+            files: u64::from(bytes == total_bytes),
+            bytes,
+            total_bytes,
+            ..hpk::Progress::default()
+        });
+        ControlFlow::Continue(())
+    };
+
+    let result = hpk::compress_file_with_progress(&options, input, output, &report);
+    reporter.finish();
+    result?;
+    Ok(())
+}
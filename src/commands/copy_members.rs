@@ -0,0 +1,62 @@
+use std::path::{Path, PathBuf};
+
+use clap::{arg, ArgMatches, Command};
+use glob::Pattern;
+
+use crate::CliResult;
+
+const ABOUT_HELP: &str = "Copy members from one hpk archive to another without recompression.
+
+The stored data of the members is copied verbatim including the compression header
+and the chunks of compressed files. The copied members replace the members of the
+destination archive with the same path. A missing destination archive is created.";
+
+pub fn cmd() -> Command {
+    fn input_parser(value: &str) -> Result<PathBuf, String> {
+        let path = Path::new(value);
+        match path.metadata() {
+            Ok(ref md) if md.is_file() => Ok(path.to_path_buf()),
+            Ok(_) | Err(_) => Err(String::from("Not a valid file")),
+        }
+    }
+    fn output_parser(value: &str) -> Result<PathBuf, String> {
+        let path = Path::new(value);
+        match path.metadata() {
+            Ok(ref md) if md.is_dir() => Err(String::from("Not a valid file")),
+            Ok(_) | Err(_) => Ok(path.to_path_buf()),
+        }
+    }
+
+    Command::new("copy-members")
+        .about("Copy members from one hpk archive to another without recompression")
+        .long_about(ABOUT_HELP)
+        .display_order(16)
+        .arg(arg!(verbose: -v "Verbosely list files processed"))
+        .arg(super::encoding_arg())
+        .arg(arg!(<src> "source hpk archive").value_parser(input_parser))
+        .arg(arg!(<dst> "destination hpk archive").value_parser(output_parser))
+        .arg(
+            arg!(<paths>... "Archive members to be copied, separated by spaces.")
+                .value_parser(Pattern::new),
+        )
+}
+
+pub fn execute(matches: &ArgMatches) -> CliResult {
+    let src = matches.get_one::<PathBuf>("src").expect("required arg");
+    let dst = matches.get_one::<PathBuf>("dst").expect("required arg");
+    let paths: Vec<_> = matches
+        .get_many::<Pattern>("paths")
+        .expect("required arg")
+        .cloned()
+        .collect();
+
+    let mut options = hpk::CopyOptions::new();
+    options.set_paths(&paths);
+    if let Some(encoding) = matches.get_one::<hpk::NameEncoding>("encoding") {
+        options.set_name_encoding(*encoding);
+    }
+    options.set_verbose(matches.get_flag("verbose"));
+
+    hpk::copy_members(&options, src, dst)?;
+    Ok(())
+}
@@ -1,8 +1,10 @@
 use std::path::{Path, PathBuf};
 
 use clap::builder::{EnumValueParser, PathBufValueParser, PossibleValue};
-use clap::{arg, ArgMatches, Command};
+use clap::{arg, ArgAction, ArgMatches, Command};
+use glob::Pattern;
 
+use super::progress::Reporter;
 use crate::CliResult;
 
 #[derive(Clone, Debug, PartialEq)]
@@ -29,9 +31,25 @@ const FILETIME_FMT_HELP: &str = "Specifies the format of the stored filedates.
 default: 'Windows file time' used by Tropico 3 and Grand Ages: Rome
 short: 'Windows file time / 2000' used by Tropico 4 and Omerta";
 
+const GAME_HELP: &str = "Applies the encoder, compressed file extensions, filedates format
+and Lua bytecode handling of the game. Explicitly given options take precedence.";
+
+const FROM_MANIFEST_HELP: &str = "Rebuilds the archive from the .hpk-manifest.json file written by
+`hpk extract --manifest`. The entries keep the order, compression and _filedates
+of the original archive. Explicitly given options take precedence and apply
+to the files that are not listed in the manifest.";
+
 const EXTENSIONS_HELP: &str = "Specifies the file extensions to be compressed. \
                                default: [lst,lua,xml,tga,dds,xtex,bin,csv]";
 
+const SMART_HELP: &str = "Tries to compress every file regardless of its extension and stores
+it uncompressed if the compression saves less than PERCENT of the file size,
+e.g. for already compressed DDS textures or OGG sounds. default: 10";
+
+const INCLUDE_HELP: &str =
+    "Compresses the files matching the pattern regardless of their extension.
+The pattern is matched against the path relative to the input directory.";
+
 pub fn cmd() -> Command {
     fn input_parser(value: &str) -> Result<PathBuf, String> {
         let dir = Path::new(value);
@@ -43,9 +61,29 @@ pub fn cmd() -> Command {
         Err(String::from("Not a valid directory"))
     }
 
+    fn like_parser(value: &str) -> Result<PathBuf, String> {
+        let file = Path::new(value);
+        match file.metadata() {
+            Ok(ref md) if md.is_file() => Ok(file.to_path_buf()),
+            Ok(_) | Err(_) => Err(String::from("Not a valid file")),
+        }
+    }
+
     Command::new("create")
         .about("Create a new hpk archive")
         .display_order(0)
+        .arg(super::game_arg()
+                .help("Applies the archive settings of the game")
+                .long_help(GAME_HELP)
+                .next_line_help(true)
+                .display_order(0))
+        .arg(arg!(--like <FILE> "Applies the settings detected in an existing hpk archive")
+                .value_parser(like_parser)
+                .conflicts_with("game")
+                .next_line_help(true)
+                .display_order(0))
+        .arg(arg!(from_manifest: --"from-manifest" "Rebuilds the archive from the .hpk-manifest.json file of the input directory")
+                .long_help(FROM_MANIFEST_HELP))
         .arg(arg!(--compress "Compress the whole hpk file").display_order(0))
         .arg(arg!(--lz4 "Sets LZ4 as encoder").display_order(10))
         .arg(arg!(chunk_size: --"chunk-size" <SIZE> "Default chunk size: 32768")
@@ -67,6 +105,30 @@ pub fn cmd() -> Command {
                 .value_delimiter(',')
                 .next_line_help(true)
                 .long_help(EXTENSIONS_HELP))
+        .arg(arg!(--smart [PERCENT] "Stores files uncompressed if the compression saves too little")
+                .value_parser(clap::value_parser!(u8).range(0..=100))
+                .default_missing_value("10")
+                .require_equals(true)
+                .conflicts_with("no_compress")
+                .long_help(SMART_HELP)
+                .next_line_help(true))
+        .arg(arg!(--include <PATTERN> "Compresses the files matching the pattern")
+                .value_parser(Pattern::new)
+                .action(ArgAction::Append)
+                .long_help(INCLUDE_HELP)
+                .next_line_help(true))
+        .arg(arg!(--exclude <PATTERN> "Never compresses the files matching the pattern")
+                .value_parser(Pattern::new)
+                .action(ArgAction::Append)
+                .next_line_help(true))
+        .arg(arg!(min_size: --"min-size" <BYTES> "Stores files smaller than BYTES uncompressed")
+                .value_parser(clap::value_parser!(u64))
+                .next_line_help(true))
+        .arg(arg!(max_size: --"max-size" <BYTES> "Stores files larger than BYTES uncompressed")
+                .value_parser(clap::value_parser!(u64))
+                .next_line_help(true))
+        .arg(super::encoding_arg().next_line_help(true))
+        .arg(arg!(verbose: -v "Verbosely list the compression decision for every file"))
         .arg(arg!(<dir> "input directory").value_parser(input_parser))
         .arg(arg!(<file> "hpk output file").value_parser(PathBufValueParser::new()))
 }
@@ -76,6 +138,16 @@ pub fn execute(matches: &ArgMatches) -> CliResult {
     let file = matches.get_one::<PathBuf>("file").expect("required arg");
 
     let mut options = hpk::CreateOptions::new();
+    if let Some(game) = matches.get_one::<hpk::Game>("game") {
+        options.with_game(*game);
+    }
+    if let Some(like) = matches.get_one::<PathBuf>("like") {
+        options.like(&hpk::info(like)?);
+    }
+    if matches.get_flag("from_manifest") {
+        let manifest = hpk::Manifest::read_from(input.join(hpk::MANIFEST_NAME))?;
+        options.with_manifest(manifest);
+    }
     if matches.get_flag("compress") {
         options.compress();
     }
@@ -100,7 +172,40 @@ pub fn execute(matches: &ArgMatches) -> CliResult {
     if matches.get_flag("no_compress") {
         options.with_extensions(Vec::new());
     }
+    if let Some(percent) = matches.get_one::<u8>("smart") {
+        options.with_min_savings(*percent);
+    }
+    if let Some(patterns) = matches.get_many::<Pattern>("include") {
+        options.set_include(&patterns.cloned().collect::<Vec<_>>());
+    }
+    if let Some(patterns) = matches.get_many::<Pattern>("exclude") {
+        options.set_exclude(&patterns.cloned().collect::<Vec<_>>());
+    }
+    if let Some(size) = matches.get_one::<u64>("min_size") {
+        options.with_min_compress_size(*size);
+    }
+    if let Some(size) = matches.get_one::<u64>("max_size") {
+        options.with_max_compress_size(*size);
+    }
+    if let Some(encoding) = matches.get_one::<hpk::NameEncoding>("encoding") {
+        options.set_name_encoding(*encoding);
+    }
+    let verbose = matches.get_flag("verbose");
+    options.set_verbose(verbose);
+
+    let reporter = Reporter::new(!verbose);
+    options.set_progress(reporter.callback());
 
-    hpk::create(&options, input, file)?;
+    let result = hpk::create(&options, input, file);
+    reporter.finish();
+    result?;
+
+    if verbose {
+        println!("{}", reporter.summary());
+        if matches.get_flag("compress") {
+            let len = file.metadata().map_err(hpk::HpkError::Io)?.len();
+            println!("compressed archive: {} bytes", len);
+        }
+    }
     Ok(())
 }
@@ -0,0 +1,35 @@
+use std::path::{Path, PathBuf};
+
+use clap::builder::PathBufValueParser;
+use clap::{arg, ArgMatches, Command};
+
+use crate::CliResult;
+
+const ABOUT_HELP: &str = "Decompress a compressed hpk archive or the data of an archive member.
+
+The output can be the input file itself. It's replaced once it's completely written.";
+
+pub fn cmd() -> Command {
+    fn input_parser(value: &str) -> Result<PathBuf, String> {
+        let path = Path::new(value);
+        match path.metadata() {
+            Ok(ref md) if md.is_file() => Ok(path.to_path_buf()),
+            Ok(_) | Err(_) => Err(String::from("Not a valid file")),
+        }
+    }
+
+    Command::new("decompress")
+        .about("Decompress a compressed hpk archive or the data of an archive member")
+        .long_about(ABOUT_HELP)
+        .display_order(13)
+        .arg(arg!(<input> "compressed hpk archive or member data").value_parser(input_parser))
+        .arg(arg!(<output> "decompressed output file").value_parser(PathBufValueParser::new()))
+}
+
+pub fn execute(matches: &ArgMatches) -> CliResult {
+    let input = matches.get_one::<PathBuf>("input").expect("required arg");
+    let output = matches.get_one::<PathBuf>("output").expect("required arg");
+
+    hpk::decompress_file(input, output)?;
+    Ok(())
+}
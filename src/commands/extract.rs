@@ -1,11 +1,19 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::ops::ControlFlow;
 use std::path::{Path, PathBuf};
 use std::process;
 
+use clap::builder::PathBufValueParser;
 use clap::{arg, ArgMatches, Command};
 use glob::Pattern;
 
+use super::progress::Reporter;
 use crate::CliResult;
 
+const TO_TAR_HELP: &str = "Write the files as tar stream to the file or `-` for stdout.
+The destination folder is omitted and all following arguments are archive members.";
+
 pub fn cmd() -> Command {
     fn input_parser(value: &str) -> Result<PathBuf, String> {
         let file = Path::new(value);
@@ -16,10 +24,10 @@ pub fn cmd() -> Command {
         }
         Err(String::from("Not a valid file"))
     }
-    fn dest_parser(value: &str) -> Result<PathBuf, String> {
+    fn tar_parser(value: &str) -> Result<PathBuf, String> {
         let dest = Path::new(value);
         match dest.metadata() {
-            Ok(ref md) if md.is_file() => Err(String::from("Not a valid directory")),
+            Ok(ref md) if md.is_dir() => Err(String::from("Not a valid file")),
             Ok(_) | Err(_) => Ok(dest.to_path_buf()),
         }
     }
@@ -28,43 +36,135 @@ pub fn cmd() -> Command {
         .about("Extract files from a hpk archive")
         .display_order(10)
         .arg(arg!(<file> "hpk archive").value_parser(input_parser))
-        .arg(arg!(<dest> "destination folder").value_parser(dest_parser))
+        .arg(arg!([dest] "destination folder")
+            .value_parser(PathBufValueParser::new())
+            .required_unless_present("to_tar"))
         .arg(arg!([paths]... "An optional list of archive members to be processed, separated by spaces.")
             .value_parser(Pattern::new))
+        .arg(super::game_arg().help("Applies the extraction settings of the game"))
+        .arg(super::encoding_arg())
         .arg(arg!(filedates: --"ignore-filedates" "Skip processing of a _filedates file and just extract it"))
+        .arg(arg!(skip_unsafe: --"skip-unsafe-paths" "Skip archive members with unsafe names instead of aborting"))
         .arg(arg!(fix_lua: --"fix-lua-files" "Fix the bytecode header of Victor Vran's or Surviving Mars' Lua files"))
+        .arg(arg!(--manifest "Write a .hpk-manifest.json file for a repack with `hpk create --from-manifest`"))
         .arg(arg!(--force "Force extraction if destination folder is not empty"))
+        .arg(arg!(to_tar: --"to-tar" <FILE> "Write the files as tar stream to the file or `-` for stdout")
+            .long_help(TO_TAR_HELP)
+            .value_parser(tar_parser))
         .arg(arg!(verbose: -v "Verbosely list files processed"))
 }
 
 pub fn execute(matches: &ArgMatches) -> CliResult {
     let input = matches.get_one::<PathBuf>("file").expect("required arg");
-    let dest = matches.get_one::<PathBuf>("dest").expect("required arg");
+    let dest = matches.get_one::<PathBuf>("dest");
+    let to_tar = matches.get_one::<PathBuf>("to_tar");
     let force = matches.get_flag("force");
     let verbose = matches.get_flag("verbose");
 
-    if let Ok(dir) = dest.read_dir() {
-        if !force && dir.count() > 0 {
-            eprintln!("error: Directory is not empty");
-            process::exit(1);
-        }
-    }
-
-    let paths = matches
+    let mut paths = matches
         .get_many::<Pattern>("paths")
         .map(|v| v.cloned().collect::<Vec<_>>())
         .unwrap_or_default();
 
+    let dest = match (dest, to_tar) {
+        (Some(dest), None) => {
+            if dest.is_file() {
+                eprintln!("error: Not a valid directory");
+                process::exit(1);
+            }
+            if let Ok(dir) = dest.read_dir() {
+                if !force && dir.count() > 0 {
+                    eprintln!("error: Directory is not empty");
+                    process::exit(1);
+                }
+            }
+            Some(dest)
+        }
+        (Some(path), Some(_)) => {
+            // The destination folder is omitted with `--to-tar`
+            let path = path.to_string_lossy();
+            let pattern = Pattern::new(&path).map_err(|e| {
+                clap::Error::raw(clap::error::ErrorKind::ValueValidation, e.to_string())
+            })?;
+            paths.insert(0, pattern);
+            None
+        }
+        (None, _) => None,
+    };
+
     let mut options = hpk::ExtractOptions::new();
     options.set_paths(&paths);
     options.set_verbose(verbose);
+    if let Some(game) = matches.get_one::<hpk::Game>("game") {
+        options.with_game(*game);
+    }
+    if let Some(encoding) = matches.get_one::<hpk::NameEncoding>("encoding") {
+        options.set_name_encoding(*encoding);
+    }
     if matches.get_flag("filedates") {
         options.skip_filedates();
     }
+    if matches.get_flag("skip_unsafe") {
+        options.skip_unsafe_paths();
+    }
     if matches.get_flag("fix_lua") {
         options.fix_lua_files();
     }
+    if matches.get_flag("manifest") {
+        options.write_manifest();
+    }
+
+    let reporter = Reporter::new(!verbose);
+    options.set_hooks(ExtractHooks {
+        progress: reporter.callback(),
+        skip_unsafe: matches.get_flag("skip_unsafe"),
+    });
 
-    hpk::extract(&options, input, dest)?;
+    let result = match (dest, to_tar) {
+        (Some(dest), None) => hpk::extract(&options, input, dest),
+        (_, Some(tar)) if tar == Path::new("-") => {
+            let out = io::BufWriter::new(io::stdout().lock());
+            hpk::extract_to_tar(&options, input, out).and_then(|mut out| Ok(out.flush()?))
+        }
+        (_, Some(tar)) => {
+            let out = File::create(tar).map_err(hpk::HpkError::Io)?;
+            hpk::extract_to_tar(&options, input, io::BufWriter::new(out))
+                .and_then(|mut out| Ok(out.flush()?))
+        }
+        (None, None) => unreachable!(),
+    };
+    reporter.finish();
+    result?;
+
+    if verbose {
+        // the file listing of `--to-tar` is written to stderr
+        match to_tar {
+            Some(_) => eprintln!("{}", reporter.summary()),
+            None => println!("{}", reporter.summary()),
+        }
+    }
     Ok(())
 }
+
+/// Reports the progress and prints the members skipped by `--skip-unsafe-paths`
+struct ExtractHooks<F> {
+    progress: F,
+    skip_unsafe: bool,
+}
+
+impl<F: Fn(&hpk::Progress<'_>)> hpk::Hooks for ExtractHooks<F> {
+    fn entry_done(&self, progress: &hpk::Progress<'_>) -> ControlFlow<()> {
+        (self.progress)(progress);
+        ControlFlow::Continue(())
+    }
+
+    fn error(&self, error: &hpk::HpkError) {
+        // the error that aborts the extraction is printed by `main`
+        match error {
+            hpk::HpkError::UnsafeDirEntryName(path) if self.skip_unsafe => {
+                eprintln!("skipping unsafe path: {}", path.display());
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use std::path::{Path, PathBuf};
+
+use clap::{arg, ArgMatches, Command};
+
+use crate::CliResult;
+
+pub fn cmd() -> Command {
+    fn input_parser(value: &str) -> Result<PathBuf, String> {
+        let path = Path::new(value);
+        match path.metadata() {
+            Ok(ref md) if md.is_file() => Ok(path.to_path_buf()),
+            Ok(_) | Err(_) => Err(String::from("Not a valid file")),
+        }
+    }
+
+    Command::new("info")
+        .about("Detect the settings and the likely origin game of a hpk archive")
+        .display_order(25)
+        .arg(arg!(<file> "hpk archive").value_parser(input_parser))
+}
+
+pub fn execute(matches: &ArgMatches) -> CliResult {
+    let input = matches.get_one::<PathBuf>("file").expect("required arg");
+    let info = hpk::info(input)?;
+
+    println!("file: {}", input.display());
+    if info.compression.is_compressed() {
+        println!("compressed: {}", info.compression);
+    } else {
+        println!("compressed: no");
+    }
+    println!("fragments_per_file: {}", info.fragments_per_file);
+    println!("files: {}", info.files);
+    println!(
+        "compressed files: {} (ZLIB={} LZ4={} ZSTD={})",
+        info.compressed_files(),
+        info.zlib_files,
+        info.lz4_files,
+        info.zstd_files
+    );
+    if let Some(chunk_size) = info.chunk_size {
+        println!("chunk_size: {}", chunk_size);
+    }
+    if !info.compressed_extensions.is_empty() {
+        println!(
+            "compressed extensions: {}",
+            info.compressed_extensions.join(",")
+        );
+    }
+    match info.filedates {
+        Some(hpk::FileDateFormat::Default) if info.filedates_prefixed => {
+            println!("filedates: default (prefixed paths)")
+        }
+        Some(hpk::FileDateFormat::Default) => println!("filedates: default"),
+        Some(hpk::FileDateFormat::Short) => println!("filedates: short"),
+        None => println!("filedates: no"),
+    }
+    println!(
+        "lua files: {} (source={} 5.1={} 5.2={} 5.3={} 5.3-crippled={} 32bit={} 64bit={})",
+        info.lua.files,
+        info.lua.source,
+        info.lua.lua51,
+        info.lua.lua52,
+        info.lua.valid,
+        info.lua.crippled,
+        info.lua.int32,
+        info.lua.int64
+    );
+    let games = info.games();
+    let games: Vec<_> = games.iter().map(|g| g.name()).collect();
+    println!("likely game: {}", games.join(", "));
+    Ok(())
+}
@@ -19,6 +19,7 @@ pub fn cmd() -> Command {
         .display_order(20)
         .arg(arg!(<file> "hpk archive").value_parser(input_parser))
         .arg(arg!([paths]...).value_parser(Pattern::new))
+        .arg(super::encoding_arg())
 }
 
 pub fn execute(matches: &ArgMatches) -> CliResult {
@@ -28,7 +29,10 @@ pub fn execute(matches: &ArgMatches) -> CliResult {
         .map(Iterator::collect::<Vec<_>>)
         .unwrap_or_default();
 
-    let walk = hpk::walk(input)?;
+    let mut walk = hpk::walk(input)?;
+    if let Some(encoding) = matches.get_one::<hpk::NameEncoding>("encoding") {
+        walk.set_name_encoding(*encoding);
+    }
 
     fn matches_path(path: &Path, paths: &[&Pattern]) -> bool {
         if paths.is_empty() {
@@ -0,0 +1,111 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use clap::{arg, ArgGroup, ArgMatches, Command};
+
+use crate::CliResult;
+
+pub fn cmd() -> Command {
+    fn input_parser(value: &str) -> Result<PathBuf, String> {
+        let file = Path::new(value);
+        match file.metadata() {
+            Ok(ref md) if md.is_file() => Ok(file.to_path_buf()),
+            Ok(_) | Err(_) => Err(String::from("Not a valid file")),
+        }
+    }
+    fn dest_parser(value: &str) -> Result<PathBuf, String> {
+        let dest = Path::new(value);
+        match dest.metadata() {
+            Ok(ref md) if md.is_file() => Err(String::from("Not a valid directory")),
+            Ok(_) | Err(_) => Ok(dest.to_path_buf()),
+        }
+    }
+    let files_cmd = |name, about| {
+        Command::new(name)
+            .about(about)
+            .arg(arg!(<files>... "Lua files").value_parser(input_parser))
+            .arg(arg!(in_place: -i --"in-place" "Rewrite the files in place"))
+            .arg(
+                arg!(output_dir: -o --"output-dir" <DIR> "Write the rewritten files to the directory")
+                    .value_parser(dest_parser),
+            )
+            .group(
+                ArgGroup::new("output")
+                    .args(["in_place", "output_dir"])
+                    .required(true),
+            )
+    };
+
+    Command::new("lua")
+        .about("Fix or cripple the bytecode header of Lua files")
+        .display_order(27)
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(files_cmd(
+            "fix",
+            "Fix the bytecode header of Victor Vran's or Surviving Mars' Lua files",
+        ))
+        .subcommand(files_cmd(
+            "cripple",
+            "Cripple the bytecode header for Victor Vran or Surviving Mars",
+        ))
+}
+
+pub fn execute(matches: &ArgMatches) -> CliResult {
+    let (fix, matches) = match matches.subcommand() {
+        Some(("fix", matches)) => (true, matches),
+        Some(("cripple", matches)) => (false, matches),
+        _ => unreachable!(),
+    };
+    let files = matches.get_many::<PathBuf>("files").expect("required arg");
+    let output_dir = matches.get_one::<PathBuf>("output_dir");
+
+    if let Some(dir) = output_dir {
+        // files with the same name would overwrite each other in the output directory
+        let mut names = HashSet::new();
+        for file in files.clone() {
+            let name = file.file_name().expect("file name");
+            if !names.insert(name) {
+                eprintln!(
+                    "error: Several files are named {}",
+                    Path::new(name).display()
+                );
+                process::exit(1);
+            }
+        }
+        fs::create_dir_all(dir).map_err(hpk::HpkError::Io)?;
+    }
+
+    for file in files {
+        let input = fs::read(file).map_err(hpk::HpkError::Io)?;
+        let mut output = Vec::with_capacity(input.len());
+        if fix {
+            io::copy(&mut &input[..], &mut hpk::lua::fix_header(&mut output))
+        } else {
+            io::copy(&mut hpk::lua::cripple_header(&input[..]), &mut output)
+        }
+        .map_err(hpk::HpkError::Io)?;
+
+        let changed = input != output;
+        let dest = match output_dir {
+            Some(dir) => dir.join(file.file_name().expect("file name")),
+            None => file.to_path_buf(),
+        };
+        if changed || output_dir.is_some() {
+            fs::write(&dest, &output).map_err(hpk::HpkError::Io)?;
+        }
+        println!(
+            "{}: {}",
+            dest.display(),
+            match (changed, fix) {
+                (true, true) => "fixed",
+                (true, false) => "crippled",
+                (false, _) => "unchanged",
+            }
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use clap::{arg, ArgMatches, Command};
+use glob::Pattern;
+use hpk::{Bits, LuaScript, LuaVersion};
+
+use crate::CliResult;
+
+pub fn cmd() -> Command {
+    fn input_parser(value: &str) -> Result<PathBuf, String> {
+        let file = Path::new(value);
+        match file.metadata() {
+            Ok(ref md) if md.is_file() => Ok(file.to_path_buf()),
+            Ok(_) | Err(_) => Err(String::from("Not a valid file")),
+        }
+    }
+
+    Command::new("lua-info")
+        .about("Print the bytecode header information of the Lua files in a hpk archive")
+        .display_order(26)
+        .arg(arg!(<file> "hpk archive").value_parser(input_parser))
+        .arg(arg!([paths]... "An optional list of archive members to be processed, separated by spaces.")
+            .value_parser(Pattern::new))
+        .arg(super::encoding_arg())
+}
+
+pub fn execute(matches: &ArgMatches) -> CliResult {
+    let input = matches.get_one::<PathBuf>("file").expect("required arg");
+    let paths = matches
+        .get_many::<Pattern>("paths")
+        .map(Iterator::collect::<Vec<_>>)
+        .unwrap_or_default();
+
+    fn matches_path(path: &Path, paths: &[&Pattern]) -> bool {
+        paths.is_empty() || paths.iter().any(|p| p.matches_path(path))
+    }
+
+    let mut walk = hpk::walk(input)?;
+    if let Some(encoding) = matches.get_one::<hpk::NameEncoding>("encoding") {
+        walk.set_name_encoding(*encoding);
+    }
+    let mut summary = BTreeMap::new();
+
+    while let Some(dent) = walk.next() {
+        let dent = dent?;
+        let is_lua = dent
+            .path()
+            .extension()
+            .and_then(OsStr::to_str)
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("lua"));
+
+        if dent.is_dir() || !is_lua || !matches_path(dent.path(), &paths) {
+            continue;
+        }
+        walk.read_file(&dent, |mut r| {
+            let buf = hpk::read_head(&mut r, hpk::lua::MAX_HEADER_LEN)?;
+
+            let category = describe(&hpk::inspect_lua(&buf));
+            println!("{}: {}", dent.path().display(), category);
+            *summary.entry(category).or_insert(0) += 1;
+            Ok(())
+        })?;
+    }
+
+    println!("summary:");
+    for (category, count) in summary {
+        println!("  {}: {}", category, count);
+    }
+    Ok(())
+}
+
+fn describe(script: &LuaScript) -> String {
+    match script {
+        LuaScript::Source => String::from("source"),
+        LuaScript::Unknown => String::from("bytecode, unknown header"),
+        LuaScript::Bytecode(hdr) => {
+            let version = match hdr.version {
+                LuaVersion::Lua51 => "5.1",
+                LuaVersion::Lua52 => "5.2",
+                LuaVersion::Lua53 => "5.3",
+            };
+            let bits = match hdr.bits() {
+                Bits::Int32 => "32-bit",
+                Bits::Int64 => "64-bit",
+            };
+            let header = if hdr.crippled { "crippled" } else { "valid" };
+            format!("bytecode, Lua {} {}, {} header", version, bits, header)
+        }
+    }
+}
@@ -0,0 +1,89 @@
+use std::path::{Path, PathBuf};
+
+use clap::builder::{EnumValueParser, PathBufValueParser, PossibleValue};
+use clap::{arg, ArgMatches, Command};
+
+use crate::CliResult;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Conflict {
+    LastWins,
+    FirstWins,
+    Error,
+}
+
+impl clap::ValueEnum for Conflict {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::LastWins, Self::FirstWins, Self::Error]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::LastWins => Some(PossibleValue::new("last-wins")),
+            Self::FirstWins => Some(PossibleValue::new("first-wins")),
+            Self::Error => Some(PossibleValue::new("error")),
+        }
+    }
+}
+
+const ABOUT_HELP: &str = "Merge several hpk archives into one archive.
+
+The member data is copied as it's stored. Compressed files stay compressed and a
+compressed input archive is merged like an uncompressed one. Use `hpk compress`
+to compress the merged archive as a whole.
+
+The `_filedates` files of the archives are merged into one file.";
+
+const CONFLICT_HELP: &str = "Specifies which file is kept if archives contain the same path.
+
+last-wins: the file of the later archive like the games load mods (default)
+first-wins: the file of the earlier archive
+error: fail without writing the archive";
+
+pub fn cmd() -> Command {
+    fn input_parser(value: &str) -> Result<PathBuf, String> {
+        let path = Path::new(value);
+        match path.metadata() {
+            Ok(ref md) if md.is_file() => Ok(path.to_path_buf()),
+            Ok(_) | Err(_) => Err(String::from("Not a valid file")),
+        }
+    }
+
+    Command::new("merge")
+        .about("Merge several hpk archives into one archive")
+        .long_about(ABOUT_HELP)
+        .display_order(14)
+        .arg(
+            arg!(--conflict <POLICY> "last-wins, first-wins or error [default: last-wins]")
+                .value_parser(EnumValueParser::<Conflict>::new())
+                .hide_possible_values(true)
+                .long_help(CONFLICT_HELP),
+        )
+        .arg(arg!(verbose: -v "Verbosely list files processed"))
+        .arg(super::encoding_arg())
+        .arg(arg!(<file> "merged hpk archive").value_parser(PathBufValueParser::new()))
+        .arg(arg!(<inputs>... "hpk archives to merge").value_parser(input_parser))
+}
+
+pub fn execute(matches: &ArgMatches) -> CliResult {
+    let file = matches.get_one::<PathBuf>("file").expect("required arg");
+    let inputs: Vec<_> = matches
+        .get_many::<PathBuf>("inputs")
+        .expect("required arg")
+        .cloned()
+        .collect();
+
+    let mut options = hpk::MergeOptions::new();
+    match matches.get_one::<Conflict>("conflict") {
+        Some(Conflict::LastWins) | None => {}
+        Some(Conflict::FirstWins) => options.set_conflict(hpk::Conflict::FirstWins),
+        Some(Conflict::Error) => options.set_conflict(hpk::Conflict::Error),
+    }
+    if let Some(encoding) = matches.get_one::<hpk::NameEncoding>("encoding") {
+        options.set_name_encoding(*encoding);
+    }
+    options.set_verbose(matches.get_flag("verbose"));
+
+    hpk::merge(&options, &inputs, file.clone())?;
+    Ok(())
+}
@@ -1,4 +1,32 @@
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{arg, Arg};
+
+pub mod compress;
+pub mod copy_members;
 pub mod create;
+pub mod decompress;
 pub mod extract;
+pub mod info;
 pub mod list;
+pub mod lua;
+pub mod lua_info;
+pub mod merge;
+pub mod overlay;
 pub mod print;
+mod progress;
+pub mod recompress;
+pub mod split;
+
+fn game_arg() -> Arg {
+    let names = hpk::Game::values().iter().map(|g| g.name());
+    arg!(--game <GAME>)
+        .value_parser(PossibleValuesParser::new(names).map(|s| s.parse::<hpk::Game>().unwrap()))
+}
+
+fn encoding_arg() -> Arg {
+    let names = hpk::NameEncoding::values().iter().map(|e| e.name());
+    arg!(--encoding <ENCODING> "Encoding of the file names in the archive [default: utf8]")
+        .value_parser(
+            PossibleValuesParser::new(names).map(|s| s.parse::<hpk::NameEncoding>().unwrap()),
+        )
+}
@@ -0,0 +1,149 @@
+use std::path::{Path, PathBuf};
+use std::process;
+
+use clap::builder::PathBufValueParser;
+use clap::{arg, ArgMatches, Command};
+
+use super::progress::Reporter;
+use crate::CliResult;
+
+const LAYERS_HELP: &str = "hpk archives or directories in load order. The files of a later
+layer override the files of the earlier layers.";
+
+pub fn cmd() -> Command {
+    fn layer_parser(value: &str) -> Result<PathBuf, String> {
+        let path = Path::new(value);
+        match path.metadata() {
+            Ok(_) => Ok(path.to_path_buf()),
+            Err(_) => Err(String::from("Not a valid file or directory")),
+        }
+    }
+    let layers_arg = || {
+        arg!(<layers>... "hpk archives or directories in load order")
+            .long_help(LAYERS_HELP)
+            .value_parser(layer_parser)
+    };
+
+    Command::new("overlay")
+        .about("Resolve the files of several hpk archives loaded on top of each other")
+        .display_order(22)
+        .subcommand_required(true)
+        .arg_required_else_help(true)
+        .subcommand(
+            Command::new("list")
+                .about("List the effective files and the layer they come from")
+                .arg(arg!(--overrides "Only list the files that override files of earlier layers"))
+                .arg(super::encoding_arg())
+                .arg(layers_arg()),
+        )
+        .subcommand(
+            Command::new("extract")
+                .about("Extract the effective files")
+                .arg(
+                    arg!(output_dir: -o --"output-dir" <DIR> "destination folder")
+                        .value_parser(PathBufValueParser::new())
+                        .required(true),
+                )
+                .arg(arg!(filedates: --"ignore-filedates" "Skip processing of the _filedates files and just extract them"))
+                .arg(arg!(fix_lua: --"fix-lua-files" "Fix the bytecode header of Victor Vran's or Surviving Mars' Lua files"))
+                .arg(arg!(--force "Force extraction if destination folder is not empty"))
+                .arg(arg!(verbose: -v "Verbosely list files processed"))
+                .arg(super::encoding_arg())
+                .arg(layers_arg()),
+        )
+}
+
+pub fn execute(matches: &ArgMatches) -> CliResult {
+    let (extract, matches) = match matches.subcommand() {
+        Some(("list", matches)) => (false, matches),
+        Some(("extract", matches)) => (true, matches),
+        _ => unreachable!(),
+    };
+
+    let mut overlay = hpk::Overlay::new();
+    if let Some(encoding) = matches.get_one::<hpk::NameEncoding>("encoding") {
+        overlay.set_name_encoding(*encoding);
+    }
+    for layer in matches.get_many::<PathBuf>("layers").expect("required arg") {
+        if layer.is_dir() {
+            overlay.add_dir(layer)?;
+        } else {
+            overlay.add_archive(layer)?;
+        }
+    }
+
+    if extract {
+        extract_files(matches, &overlay)
+    } else {
+        list_files(matches, &overlay);
+        Ok(())
+    }
+}
+
+fn list_files(matches: &ArgMatches, overlay: &hpk::Overlay) {
+    let only_overrides = matches.get_flag("overrides");
+
+    for entry in overlay.entries() {
+        if only_overrides && entry.overrides().is_empty() {
+            continue;
+        }
+        let layer = overlay.layer_path(entry.layer());
+        if entry.overrides().is_empty() {
+            println!("{}: {}", entry.path().display(), layer.display());
+        } else {
+            let overrides: Vec<_> = entry
+                .overrides()
+                .iter()
+                .map(|&l| overlay.layer_path(l).display().to_string())
+                .collect();
+            println!(
+                "{}: {} (overrides {})",
+                entry.path().display(),
+                layer.display(),
+                overrides.join(", ")
+            );
+        }
+    }
+}
+
+fn extract_files(matches: &ArgMatches, overlay: &hpk::Overlay) -> CliResult {
+    let dest = matches
+        .get_one::<PathBuf>("output_dir")
+        .expect("required arg");
+    let verbose = matches.get_flag("verbose");
+
+    if dest.is_file() {
+        eprintln!("error: Not a valid directory");
+        process::exit(1);
+    }
+    if let Ok(dir) = dest.read_dir() {
+        if !matches.get_flag("force") && dir.count() > 0 {
+            eprintln!("error: Directory is not empty");
+            process::exit(1);
+        }
+    }
+
+    let mut options = hpk::ExtractOptions::new();
+    options.set_verbose(verbose);
+    if let Some(encoding) = matches.get_one::<hpk::NameEncoding>("encoding") {
+        options.set_name_encoding(*encoding);
+    }
+    if matches.get_flag("filedates") {
+        options.skip_filedates();
+    }
+    if matches.get_flag("fix_lua") {
+        options.fix_lua_files();
+    }
+
+    let reporter = Reporter::new(!verbose);
+    options.set_progress(reporter.callback());
+
+    let result = overlay.extract(&options, dest);
+    reporter.finish();
+    result?;
+
+    if verbose {
+        println!("{}", reporter.summary());
+    }
+    Ok(())
+}
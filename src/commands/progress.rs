@@ -0,0 +1,87 @@
+use std::cell::Cell;
+use std::io::{self, IsTerminal, Write};
+use std::rc::Rc;
+
+use hpk::{Progress, ProgressStage};
+
+const LABEL_WIDTH: usize = 60;
+
+/// Sums up the sizes of the processed files and draws a progress line on stderr
+#[derive(Default)]
+pub struct Reporter {
+    bar: bool,
+    files: Cell<u64>,
+    inflated: Cell<u64>,
+    stored: Cell<u64>,
+}
+
+impl Reporter {
+    /// The progress line is only drawn if stderr is a terminal
+    pub fn new(bar: bool) -> Rc<Self> {
+        Rc::new(Reporter {
+            bar: bar && io::stderr().is_terminal(),
+            ..Reporter::default()
+        })
+    }
+
+    pub fn callback(self: &Rc<Self>) -> impl Fn(&Progress<'_>) + 'static {
+        let reporter = Rc::clone(self);
+        move |progress| reporter.update(progress)
+    }
+
+    fn update(&self, progress: &Progress<'_>) {
+        let entry = progress
+            .entry
+            .filter(|_| progress.stage == ProgressStage::Entries);
+        if let Some(entry) = &entry {
+            self.files.set(self.files.get() + 1);
+            self.inflated.set(self.inflated.get() + entry.inflated);
+            self.stored.set(self.stored.get() + entry.stored);
+        }
+        if !self.bar {
+            return;
+        }
+        let percent = match progress.total_bytes {
+            0 => 100,
+            total => progress.bytes * 100 / total,
+        };
+        let label = match (progress.stage, &progress.entry) {
+            (ProgressStage::Measure, _) => String::from("measuring archive"),
+            (_, Some(entry)) => entry.path.display().to_string(),
+            (_, None) => String::new(),
+        };
+        let skip = label.chars().count().saturating_sub(LABEL_WIDTH);
+        let label = label.chars().skip(skip).collect::<String>();
+
+        let mut stderr = io::stderr().lock();
+        let _ = write!(
+            stderr,
+            "\r\x1b[K[{}/{}] {:3}% {}",
+            progress.files, progress.total_files, percent, label
+        );
+        let _ = stderr.flush();
+    }
+
+    /// Clears the progress line
+    pub fn finish(&self) {
+        if self.bar {
+            eprint!("\r\x1b[K");
+        }
+    }
+
+    /// Returns the number of files and the stored and inflated sizes with the compression ratio
+    pub fn summary(&self) -> String {
+        let (inflated, stored) = (self.inflated.get(), self.stored.get());
+        let ratio = match inflated {
+            0 => 100.0,
+            n => stored as f64 * 100.0 / n as f64,
+        };
+        format!(
+            "{} files, {} bytes stored, {} bytes inflated, ratio {:.1}%",
+            self.files.get(),
+            stored,
+            inflated,
+            ratio
+        )
+    }
+}
@@ -0,0 +1,103 @@
+use std::path::{Path, PathBuf};
+
+use clap::builder::{EnumValueParser, PathBufValueParser, PossibleValue};
+use clap::{arg, ArgMatches, Command};
+
+use crate::CliResult;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Codec {
+    Zlib,
+    Lz4,
+    Zstd,
+    None,
+}
+
+impl clap::ValueEnum for Codec {
+    fn value_variants<'a>() -> &'a [Self] {
+        &[Self::Zlib, Self::Lz4, Self::Zstd, Self::None]
+    }
+
+    fn to_possible_value(&self) -> Option<PossibleValue> {
+        match self {
+            Self::Zlib => Some(PossibleValue::new("zlib")),
+            Self::Lz4 => Some(PossibleValue::new("lz4")),
+            Self::Zstd => Some(PossibleValue::new("zstd")),
+            Self::None => Some(PossibleValue::new("none")),
+        }
+    }
+}
+
+const ABOUT_HELP: &str = "Rewrite the members of a hpk archive with a different encoder.
+
+The entries keep their paths and order and the _filedates file is kept. Members
+that are already compressed with the encoder and chunk size are copied as they're
+stored. An archive that is compressed as a whole is compressed with the new
+encoder as well.
+
+The output can be the input file itself. It's replaced once it's completely written.";
+
+const EXTENSIONS_HELP: &str = "Specifies the file extensions to be compressed. \
+                               default: the members compressed in the input archive";
+
+pub fn cmd() -> Command {
+    fn input_parser(value: &str) -> Result<PathBuf, String> {
+        let path = Path::new(value);
+        match path.metadata() {
+            Ok(ref md) if md.is_file() => Ok(path.to_path_buf()),
+            Ok(_) | Err(_) => Err(String::from("Not a valid file")),
+        }
+    }
+
+    Command::new("recompress")
+        .about("Rewrite the members of a hpk archive with a different encoder")
+        .long_about(ABOUT_HELP)
+        .display_order(17)
+        .arg(
+            arg!(--codec <CODEC> "Encoder of the compressed members, `none` decompresses them")
+                .value_parser(EnumValueParser::<Codec>::new())
+                .required(true),
+        )
+        .arg(
+            arg!(chunk_size: --"chunk-size" <SIZE> "Default chunk size: 32768")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .next_line_help(true),
+        )
+        .arg(
+            arg!(--extensions <EXT>...)
+                .num_args(1..)
+                .value_delimiter(',')
+                .next_line_help(true)
+                .long_help(EXTENSIONS_HELP),
+        )
+        .arg(arg!(verbose: -v "Verbosely list files processed"))
+        .arg(super::encoding_arg())
+        .arg(arg!(<input> "hpk archive").value_parser(input_parser))
+        .arg(arg!(<output> "recompressed hpk archive").value_parser(PathBufValueParser::new()))
+}
+
+pub fn execute(matches: &ArgMatches) -> CliResult {
+    let input = matches.get_one::<PathBuf>("input").expect("required arg");
+    let output = matches.get_one::<PathBuf>("output").expect("required arg");
+
+    let mut options = hpk::RecompressOptions::new();
+    match matches.get_one::<Codec>("codec").expect("required arg") {
+        Codec::Zlib => options.set_codec(hpk::Compression::Zlib),
+        Codec::Lz4 => options.set_codec(hpk::Compression::Lz4),
+        Codec::Zstd => options.set_codec(hpk::Compression::Zstd),
+        Codec::None => options.set_codec(hpk::Compression::None),
+    }
+    if let Some(chunk_size) = matches.get_one::<u32>("chunk_size") {
+        options.with_chunk_size(*chunk_size);
+    }
+    if let Some(extensions) = matches.get_many::<String>("extensions") {
+        options.with_extensions(extensions.map(ToOwned::to_owned).collect());
+    }
+    if let Some(encoding) = matches.get_one::<hpk::NameEncoding>("encoding") {
+        options.set_name_encoding(*encoding);
+    }
+    options.set_verbose(matches.get_flag("verbose"));
+
+    hpk::recompress(&options, input, output)?;
+    Ok(())
+}
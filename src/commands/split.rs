@@ -0,0 +1,90 @@
+use std::path::{Path, PathBuf};
+use std::process;
+
+use clap::builder::PathBufValueParser;
+use clap::{arg, ArgMatches, Command};
+
+use crate::CliResult;
+
+const ABOUT_HELP: &str = "Split a hpk archive into several archives.
+
+Every top-level directory is written to an archive named after the directory and
+the files of the root directory to an archive named after the input archive.
+
+The member data is copied as it's stored and every archive gets the lines of the
+`_filedates` file for its entries.";
+
+const MAX_SIZE_HELP: &str = "Splits the archive into parts of at most SIZE bytes named
+<name>.1.hpk, <name>.2.hpk, ... instead of one archive per top-level directory.
+The size accepts the suffixes K, M and G. A file that is larger than SIZE is
+written to a part of its own.";
+
+pub fn cmd() -> Command {
+    fn input_parser(value: &str) -> Result<PathBuf, String> {
+        let path = Path::new(value);
+        match path.metadata() {
+            Ok(ref md) if md.is_file() => Ok(path.to_path_buf()),
+            Ok(_) | Err(_) => Err(String::from("Not a valid file")),
+        }
+    }
+    fn size_parser(value: &str) -> Result<u64, String> {
+        let (num, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+            Some(pos) => value.split_at(pos),
+            None => (value, ""),
+        };
+        let unit = match unit {
+            "" => 1,
+            "K" | "k" => 1 << 10,
+            "M" | "m" => 1 << 20,
+            "G" | "g" => 1 << 30,
+            _ => return Err(String::from("Unknown size suffix")),
+        };
+        match num.parse::<u64>().ok().and_then(|n| n.checked_mul(unit)) {
+            Some(0) | None => Err(String::from("Not a valid size")),
+            Some(size) => Ok(size),
+        }
+    }
+
+    Command::new("split")
+        .about("Split a hpk archive by top-level directory or size")
+        .long_about(ABOUT_HELP)
+        .display_order(15)
+        .arg(
+            arg!(max_size: --"max-size" <SIZE> "Split into parts of at most SIZE bytes")
+                .long_help(MAX_SIZE_HELP)
+                .value_parser(size_parser),
+        )
+        .arg(arg!(--force "Force splitting if destination folder is not empty"))
+        .arg(arg!(verbose: -v "Verbosely list files processed"))
+        .arg(super::encoding_arg())
+        .arg(arg!(<file> "hpk archive").value_parser(input_parser))
+        .arg(arg!(<dest> "destination folder").value_parser(PathBufValueParser::new()))
+}
+
+pub fn execute(matches: &ArgMatches) -> CliResult {
+    let input = matches.get_one::<PathBuf>("file").expect("required arg");
+    let dest = matches.get_one::<PathBuf>("dest").expect("required arg");
+
+    if dest.is_file() {
+        eprintln!("error: Not a valid directory");
+        process::exit(1);
+    }
+    if let Ok(dir) = dest.read_dir() {
+        if !matches.get_flag("force") && dir.count() > 0 {
+            eprintln!("error: Directory is not empty");
+            process::exit(1);
+        }
+    }
+
+    let mut options = hpk::SplitOptions::new();
+    if let Some(max_size) = matches.get_one::<u64>("max_size") {
+        options.with_max_size(*max_size);
+    }
+    if let Some(encoding) = matches.get_one::<hpk::NameEncoding>("encoding") {
+        options.set_name_encoding(*encoding);
+    }
+    options.set_verbose(matches.get_flag("verbose"));
+
+    hpk::split(&options, input, dest)?;
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use tempfile::NamedTempFile;
+
+use crate::HpkResult;
+
+/// Output file that is written to a temp file next to the destination
+///
+/// The destination is only replaced by [`commit`] with a rename, so a failed or
+/// interrupted write never leaves a truncated archive behind. The temp file is
+/// removed if it's dropped without a commit.
+///
+/// [`commit`]: AtomicFile::commit
+pub(crate) struct AtomicFile {
+    file: NamedTempFile,
+    dest: PathBuf,
+}
+
+impl AtomicFile {
+    pub(crate) fn new(dest: &Path) -> HpkResult<Self> {
+        let dir = match dest.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let name = dest.file_name().unwrap_or_default().to_string_lossy();
+        let file = tempfile::Builder::new()
+            .prefix(&format!(".{}.", name))
+            .suffix(".tmp")
+            .tempfile_in(dir)?;
+        Ok(AtomicFile {
+            file,
+            dest: dest.to_path_buf(),
+        })
+    }
+
+    pub(crate) fn as_file_mut(&mut self) -> &mut File {
+        self.file.as_file_mut()
+    }
+
+    /// Replaces the destination with the written file
+    ///
+    /// The permissions of an existing destination are kept. A new file gets the
+    /// permissions `0644` on Unix instead of the restrictive ones of the temp file.
+    pub(crate) fn commit(self) -> HpkResult<()> {
+        self.file.as_file().sync_all()?;
+        let permissions = match fs::metadata(&self.dest) {
+            Ok(md) => Some(md.permissions()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => default_permissions(),
+            Err(err) => return Err(err.into()),
+        };
+        if let Some(permissions) = permissions {
+            fs::set_permissions(self.file.path(), permissions)?;
+        }
+        self.file.persist(&self.dest).map_err(|e| e.error)?;
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn default_permissions() -> Option<fs::Permissions> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Some(fs::Permissions::from_mode(0o644))
+}
+
+#[cfg(not(unix))]
+fn default_permissions() -> Option<fs::Permissions> {
+    None
+}
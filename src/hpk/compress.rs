@@ -85,6 +85,13 @@ impl Decoder for Zstd {
     }
 }
 
+impl Encoder for Zstd {
+    fn encode_chunk<W: Write>(chunk: &[u8], w: &mut W) -> io::Result<u64> {
+        let buf = zstd::bulk::compress(chunk, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+        io::copy(&mut Cursor::new(buf), w)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -99,6 +106,16 @@ mod tests {
         assert_eq!(input, &output[..]);
     }
 
+    #[test]
+    fn zstd() {
+        let input = "Hello World".as_bytes();
+        let mut buf = vec![];
+        let mut output = vec![];
+        Zstd::encode_chunk(input, &mut buf).unwrap();
+        Zstd::decode_chunk(&buf, &mut output).unwrap();
+        assert_eq!(input, &output[..]);
+    }
+
     #[test]
     fn lz4_block() {
         let input = "Hello World".as_bytes();
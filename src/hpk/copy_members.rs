@@ -0,0 +1,112 @@
+use std::path::Path;
+
+use glob::Pattern;
+
+use crate::merge::{merge_filedates, Conflict};
+use crate::overlay::Overlay;
+use crate::write::write_archive;
+use crate::{HpkError, HpkResult, NameEncoding};
+
+#[derive(Default)]
+pub struct CopyOptions {
+    paths: Vec<Pattern>,
+    name_encoding: NameEncoding,
+    verbose: bool,
+}
+
+impl CopyOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only the members matching one of the patterns are copied, all members if empty
+    pub fn set_paths(&mut self, paths: &[Pattern]) {
+        self.paths = paths.to_vec();
+    }
+
+    pub fn set_name_encoding(&mut self, encoding: NameEncoding) {
+        self.name_encoding = encoding;
+    }
+
+    /// Lists every copied file
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    fn matches(&self, path: &Path) -> bool {
+        self.paths.is_empty() || self.paths.iter().any(|p| p.matches_path(path))
+    }
+}
+
+/// Copies the members of the source archive into the destination archive
+///
+/// The stored data is copied verbatim, the compression header and the chunks of a
+/// compressed member are neither decompressed nor recompressed. The copied members
+/// replace the members of the destination with the same path, a missing destination
+/// archive is created. The `_filedates` lines of the copied members are merged into
+/// the `_filedates` file of the destination. A file that is a directory in the other
+/// archive fails with [`HpkError::MergeConflict`] like [`merge`] does.
+///
+/// The destination is rewritten to a temp file and replaced once it's completely
+/// written. A compressed destination archive is written uncompressed.
+///
+/// Returns the number of copied files.
+///
+/// [`merge`]: crate::merge
+pub fn copy_members<P: AsRef<Path>>(options: &CopyOptions, src: P, dst: P) -> HpkResult<u64> {
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    let mut overlay = Overlay::new();
+    overlay.set_name_encoding(options.name_encoding);
+    let mut layers = 0;
+    if dst.exists() {
+        overlay.add_archive(dst)?;
+        layers += 1;
+    }
+    let src_layer = layers;
+    overlay.add_archive_matching(src, |path| options.matches(path))?;
+    layers += 1;
+
+    for entry in overlay.entries() {
+        if overlay.dirs().contains(entry.path()) {
+            return Err(HpkError::MergeConflict(entry.path().to_path_buf()));
+        }
+    }
+
+    // only the lines of the copied entries are taken from the source archive
+    let keep = |layer, path: &Path| {
+        layer != src_layer || overlay.get(path).is_some() || overlay.dirs().contains(path)
+    };
+    let filedates = merge_filedates(
+        &overlay,
+        options.name_encoding,
+        layers,
+        Conflict::LastWins,
+        keep,
+    )?;
+
+    let mut copied = 0;
+    let files = overlay.entries().map(|e| e.path());
+    let dirs = overlay.dirs().iter().map(|p| p.as_path());
+    write_archive(
+        dst,
+        options.name_encoding,
+        files,
+        dirs,
+        filedates.as_deref(),
+        |writer, path| {
+            let entry = overlay.get(path).expect("file of the archives");
+            overlay.read_file(entry, |r| {
+                writer.add_file(path, r)?;
+                Ok(())
+            })?;
+            if entry.layer() == src_layer {
+                copied += 1;
+                if options.verbose {
+                    println!("{}", path.display());
+                }
+            }
+            Ok(())
+        },
+    )?;
+    Ok(copied)
+}
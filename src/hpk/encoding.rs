@@ -0,0 +1,95 @@
+use std::borrow::Cow;
+use std::ffi::OsStr;
+use std::fmt;
+use std::str::{self, FromStr};
+
+use encoding_rs::{WINDOWS_1251, WINDOWS_1252};
+use serde::{Deserialize, Serialize};
+
+/// Encoding of the entry names and the paths of the `_filedates` file
+///
+/// Older localized packs store the names in a Windows codepage instead of UTF-8.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum NameEncoding {
+    #[default]
+    Utf8,
+    /// Western European names (Windows-1252)
+    Cp1252,
+    /// Cyrillic names (Windows-1251)
+    Cp1251,
+    /// Invalid UTF-8 sequences are replaced when reading and
+    /// non-Unicode paths are converted lossily when writing.
+    Lossy,
+}
+
+impl NameEncoding {
+    pub fn values() -> &'static [NameEncoding] {
+        &[
+            NameEncoding::Utf8,
+            NameEncoding::Cp1252,
+            NameEncoding::Cp1251,
+            NameEncoding::Lossy,
+        ]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            NameEncoding::Utf8 => "utf8",
+            NameEncoding::Cp1252 => "cp1252",
+            NameEncoding::Cp1251 => "cp1251",
+            NameEncoding::Lossy => "lossy",
+        }
+    }
+
+    /// Returns `None` if the name is not valid in the encoding
+    pub(crate) fn decode(self, buf: &[u8]) -> Option<Cow<'_, str>> {
+        match self {
+            NameEncoding::Utf8 => str::from_utf8(buf).ok().map(Cow::Borrowed),
+            NameEncoding::Cp1252 => {
+                WINDOWS_1252.decode_without_bom_handling_and_without_replacement(buf)
+            }
+            NameEncoding::Cp1251 => {
+                WINDOWS_1251.decode_without_bom_handling_and_without_replacement(buf)
+            }
+            NameEncoding::Lossy => Some(String::from_utf8_lossy(buf)),
+        }
+    }
+
+    /// Returns `None` if the name is not representable in the encoding
+    pub(crate) fn encode(self, name: &OsStr) -> Option<Cow<'_, [u8]>> {
+        let codepage = match self {
+            NameEncoding::Utf8 => return name.to_str().map(|s| Cow::Borrowed(s.as_bytes())),
+            NameEncoding::Lossy => {
+                return Some(match name.to_string_lossy() {
+                    Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+                    Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+                })
+            }
+            NameEncoding::Cp1252 => WINDOWS_1252,
+            NameEncoding::Cp1251 => WINDOWS_1251,
+        };
+        match codepage.encode(name.to_str()?) {
+            (_, _, true) => None,
+            (buf, _, false) => Some(buf),
+        }
+    }
+}
+
+impl fmt::Display for NameEncoding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for NameEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        NameEncoding::values()
+            .iter()
+            .find(|e| e.name() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown encoding: {}", s))
+    }
+}
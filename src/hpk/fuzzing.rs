@@ -0,0 +1,70 @@
+//! Entry points of the internal parsers for the fuzz targets in `fuzz/`
+//!
+//! The regression corpus in `fuzz/corpus` is replayed by `tests/fuzz-corpus.rs`.
+use std::io::{self, Cursor, Read, Write};
+use std::path::Path;
+
+use crate::compress::Decoder;
+use crate::{copy, decompress, lua};
+use crate::{CompressionHeader, DirEntry, Header, NameEncoding};
+
+pub fn header(data: &[u8]) {
+    if let Ok(hdr) = Header::read_from(data) {
+        hdr.filesystem_entries();
+    }
+}
+
+pub fn dir_entries(data: &[u8]) {
+    let mut r = Cursor::new(data);
+    while r.position() < data.len() as u64 {
+        if DirEntry::read_from(Path::new("root"), 1, NameEncoding::Lossy, &mut r).is_err() {
+            break;
+        }
+    }
+}
+
+pub fn compression_header(data: &[u8]) {
+    let mut r = data;
+    let _ = CompressionHeader::read_from(data.len() as u64, &mut r);
+}
+
+/// Traverses the archive and reads all files
+pub fn walk(data: &[u8]) {
+    let Ok(mut file) = tempfile::NamedTempFile::new() else {
+        return;
+    };
+    if file.write_all(data).is_err() {
+        return;
+    }
+    let Ok(mut walk) = crate::walk(file.path()) else {
+        return;
+    };
+    while let Some(entry) = walk.next() {
+        if let Ok(entry) = entry {
+            let _ = walk.read_file(&entry, |mut r| {
+                copy(&mut r, &mut io::sink())?;
+                Ok(())
+            });
+        }
+    }
+    let _ = crate::info(file.path());
+}
+
+/// Decodes the data as single chunk and as compressed file with a compression header
+pub fn decode<T: Decoder>(data: &[u8]) {
+    let _ = T::decode_chunk(data, &mut io::sink());
+    let mut r = data;
+    let _ = decompress::<T>(data.len() as u64, &mut r, &mut io::sink());
+}
+
+/// Parses and rewrites the Lua bytecode header
+pub fn lua(data: &[u8]) {
+    let _ = lua::parser::header(data);
+    lua::inspect(data);
+
+    let mut buf = vec![];
+    let _ = lua::cripple_header(data).read_to_end(&mut buf);
+    let mut w = lua::fix_header(vec![]);
+    let _ = w.write_all(&buf);
+    let _ = w.flush();
+}
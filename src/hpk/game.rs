@@ -0,0 +1,101 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::FileDateFormat;
+
+/// Haemimont Engine titles with known archive settings
+///
+/// A game bundles the encoder, the compressed file extensions, the format of
+/// the `_filedates` file and the handling of the Lua bytecode header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Game {
+    Tropico3,
+    Tropico4,
+    Tropico5,
+    Omerta,
+    GrandAgesRome,
+    VictorVran,
+    SurvivingMars,
+    /// Surviving Mars hangs when hpk files in the `DLC` folder contain compressed files.
+    SurvivingMarsDlc,
+}
+
+impl Game {
+    pub fn values() -> &'static [Game] {
+        &[
+            Game::Tropico3,
+            Game::Tropico4,
+            Game::Tropico5,
+            Game::Omerta,
+            Game::GrandAgesRome,
+            Game::VictorVran,
+            Game::SurvivingMars,
+            Game::SurvivingMarsDlc,
+        ]
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Game::Tropico3 => "tropico3",
+            Game::Tropico4 => "tropico4",
+            Game::Tropico5 => "tropico5",
+            Game::Omerta => "omerta",
+            Game::GrandAgesRome => "grand-ages-rome",
+            Game::VictorVran => "victor-vran",
+            Game::SurvivingMars => "surviving-mars",
+            Game::SurvivingMarsDlc => "surviving-mars-dlc",
+        }
+    }
+
+    /// Victor Vran and Surviving Mars use LZ4 instead of ZLIB.
+    pub(crate) fn uses_lz4(self) -> bool {
+        std::matches!(
+            self,
+            Game::VictorVran | Game::SurvivingMars | Game::SurvivingMarsDlc
+        )
+    }
+
+    pub(crate) fn compresses_files(self) -> bool {
+        !std::matches!(self, Game::SurvivingMarsDlc)
+    }
+
+    /// The games with Lua 5.3 scripts ship bytecode with a crippled header.
+    pub(crate) fn cripples_lua_files(self) -> bool {
+        std::matches!(
+            self,
+            Game::VictorVran | Game::SurvivingMars | Game::SurvivingMarsDlc
+        )
+    }
+
+    /// Tropico 3, Tropico 4 and Omerta ship Lua 5.1 bytecode.
+    pub(crate) fn ships_lua51(self) -> bool {
+        std::matches!(self, Game::Tropico3 | Game::Tropico4 | Game::Omerta)
+    }
+
+    /// Tropico 5 and later titles don't use a `_filedates` file anymore.
+    pub(crate) fn filedates_format(self) -> Option<FileDateFormat> {
+        match self {
+            Game::Tropico3 | Game::GrandAgesRome => Some(FileDateFormat::Default),
+            Game::Tropico4 | Game::Omerta => Some(FileDateFormat::Short),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Game {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Game {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Game::values()
+            .iter()
+            .find(|g| g.name() == s)
+            .copied()
+            .ok_or_else(|| format!("unknown game: {}", s))
+    }
+}
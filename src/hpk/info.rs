@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Component, Path};
+
+use crate::lua;
+use crate::lua::parser::{Bits, LuaVersion};
+use crate::lua::LuaScript;
+use crate::walk::walk;
+use crate::HpkResult;
+use crate::{
+    copy, get_compression, read_head, Compression, CompressionHeader, FileDateFormat, Game,
+    NameEncoding,
+};
+
+/// Recent file times are above 10^17 in the default format and below 10^14 in the
+/// short format (Windows file time / 2000).
+const SHORT_FILEDATES_LIMIT: i64 = 10_000_000_000_000_000;
+
+/// Traits of an existing hpk archive
+///
+/// The traits are used to infer the likely origin game of an archive and to
+/// create new archives with the same settings. See [`CreateOptions::like`].
+///
+/// [`CreateOptions::like`]: crate::CreateOptions::like
+#[derive(Debug)]
+pub struct ArchiveInfo {
+    /// Encoder used for the compression of the whole archive
+    pub compression: Compression,
+    pub fragments_per_file: u32,
+    pub files: usize,
+    pub zlib_files: usize,
+    pub lz4_files: usize,
+    pub zstd_files: usize,
+    /// Most common chunk size of the compressed files
+    pub chunk_size: Option<u32>,
+    /// File extensions of the compressed files
+    pub compressed_extensions: Vec<String>,
+    pub filedates: Option<FileDateFormat>,
+    /// Paths of the `_filedates` file are prefixed by the basename of the archive
+    pub filedates_prefixed: bool,
+    pub lua: LuaStats,
+}
+
+#[derive(Debug, Default)]
+pub struct LuaStats {
+    pub files: usize,
+    /// Plain Lua files
+    pub source: usize,
+    pub lua51: usize,
+    pub lua52: usize,
+    /// Lua 5.3 bytecode with a valid header
+    pub valid: usize,
+    /// Lua 5.3 bytecode with the crippled header of Victor Vran and Surviving Mars
+    pub crippled: usize,
+    pub int32: usize,
+    pub int64: usize,
+}
+
+impl ArchiveInfo {
+    /// Returns the encoder used by most of the compressed files
+    pub fn compressor(&self) -> Compression {
+        let counts = [
+            (self.zlib_files, Compression::Zlib),
+            (self.lz4_files, Compression::Lz4),
+            (self.zstd_files, Compression::Zstd),
+        ];
+        match counts
+            .iter()
+            .filter(|(n, _)| *n > 0)
+            .max_by_key(|(n, _)| *n)
+        {
+            Some((_, c)) => *c,
+            None => self.compression,
+        }
+    }
+
+    pub fn compressed_files(&self) -> usize {
+        self.zlib_files + self.lz4_files + self.zstd_files
+    }
+
+    /// Returns the games which produce archives with the same traits
+    ///
+    /// The list is empty if the traits contradict each other, e.g. Lua 5.2 bytecode
+    /// with the short `_filedates` format of Tropico 4 and Omerta.
+    pub fn games(&self) -> Vec<Game> {
+        if self.lua.crippled > 0 {
+            return if self.lua.int64 > 0 {
+                if self.compressed_files() == 0 {
+                    vec![Game::SurvivingMarsDlc, Game::SurvivingMars]
+                } else {
+                    vec![Game::SurvivingMars]
+                }
+            } else {
+                vec![Game::VictorVran]
+            };
+        }
+        match self.compressor() {
+            Compression::Zstd => return vec![Game::SurvivingMars],
+            Compression::Lz4 => return vec![Game::VictorVran, Game::SurvivingMars],
+            _ => {}
+        }
+        let mut games = match self.filedates {
+            Some(FileDateFormat::Short) => vec![Game::Tropico4, Game::Omerta],
+            Some(FileDateFormat::Default) if self.filedates_prefixed => vec![Game::GrandAgesRome],
+            Some(FileDateFormat::Default) => vec![Game::Tropico3, Game::GrandAgesRome],
+            None if self.lua.lua51 > 0 => {
+                let games = Game::values().iter().copied();
+                games.filter(|g| g.ships_lua51()).collect()
+            }
+            None if self.compressed_files() == 0 && self.files > 0 => {
+                vec![Game::SurvivingMarsDlc, Game::Tropico5]
+            }
+            None => vec![Game::Tropico5],
+        };
+        // The version of the Lua bytecode tells the older games apart
+        if self.lua.lua51 > 0 {
+            games.retain(|g| g.ships_lua51());
+        } else if self.lua.lua52 > 0 || self.lua.valid > 0 {
+            games.retain(|g| !g.ships_lua51());
+        }
+        games
+    }
+}
+
+/// Collects the traits of an existing hpk archive
+pub fn info<P: AsRef<Path>>(file: P) -> HpkResult<ArchiveInfo> {
+    let file = file.as_ref();
+
+    let (compression, whole_chunk_size) = {
+        let mut f = File::open(file)?;
+        let compression = get_compression(&mut f)?;
+        if compression.is_compressed() {
+            let len = f.metadata()?.len();
+            let hdr = CompressionHeader::read_from(len, &mut f)?;
+            (compression, Some(hdr.chunk_size))
+        } else {
+            (compression, None)
+        }
+    };
+
+    // The names are only used for the detection of the compressed extensions
+    // and the prefix of the `_filedates` paths.
+    let mut walk = walk(file)?;
+    walk.set_name_encoding(NameEncoding::Lossy);
+    let mut info = ArchiveInfo {
+        compression,
+        fragments_per_file: walk.header().fragments_per_file,
+        files: 0,
+        zlib_files: 0,
+        lz4_files: 0,
+        zstd_files: 0,
+        chunk_size: None,
+        compressed_extensions: vec![],
+        filedates: None,
+        filedates_prefixed: false,
+        lua: LuaStats::default(),
+    };
+    let mut chunk_sizes = HashMap::new();
+    let mut root_names = vec![];
+    let mut filedates = None;
+
+    while let Some(entry) = walk.next() {
+        let entry = entry?;
+        if entry.depth() == 1 {
+            root_names.push(entry.file_name().to_os_string());
+        }
+        if entry.is_dir() {
+            continue;
+        }
+        if entry.depth() == 1 && entry.path() == Path::new("_filedates") {
+            walk.read_file(&entry, |mut r| {
+                let mut buf = vec![];
+                copy(&mut r, &mut buf)?;
+                filedates = Some(buf);
+                Ok(())
+            })?;
+            continue;
+        }
+        info.files += 1;
+
+        let ext = entry
+            .path()
+            .extension()
+            .and_then(OsStr::to_str)
+            .map_or(String::new(), str::to_ascii_lowercase);
+
+        walk.read_file(&entry, |mut r| {
+            let compression = get_compression(&mut r)?;
+            if compression.is_compressed() {
+                match compression {
+                    Compression::Zlib => info.zlib_files += 1,
+                    Compression::Lz4 => info.lz4_files += 1,
+                    _ => info.zstd_files += 1,
+                }
+                let hdr = CompressionHeader::read_from(r.len(), &mut r)?;
+                *chunk_sizes.entry(hdr.chunk_size).or_insert(0) += 1;
+                if !info.compressed_extensions.contains(&ext) {
+                    info.compressed_extensions.push(ext.clone());
+                }
+                r.rewind()?;
+            }
+            if ext == "lua" {
+                let buf = read_head(&mut r, lua::MAX_HEADER_LEN)?;
+                info.lua.add(&buf);
+            }
+            Ok(())
+        })?;
+    }
+
+    info.chunk_size = chunk_sizes
+        .into_iter()
+        .max_by_key(|(_, n)| *n)
+        .map(|(size, _)| size)
+        .or(whole_chunk_size);
+
+    if let Some(buf) = filedates {
+        let (fmt, prefixed) = detect_filedates(&buf, &root_names);
+        info.filedates = fmt;
+        info.filedates_prefixed = prefixed;
+    }
+
+    Ok(info)
+}
+
+impl LuaStats {
+    fn add(&mut self, buf: &[u8]) {
+        self.files += 1;
+        let hdr = match lua::inspect(buf) {
+            LuaScript::Bytecode(hdr) => hdr,
+            LuaScript::Source => {
+                self.source += 1;
+                return;
+            }
+            LuaScript::Unknown => return,
+        };
+        match hdr.version {
+            LuaVersion::Lua51 => self.lua51 += 1,
+            LuaVersion::Lua52 => self.lua52 += 1,
+            LuaVersion::Lua53 if hdr.crippled => self.crippled += 1,
+            LuaVersion::Lua53 => self.valid += 1,
+        }
+        match hdr.bits() {
+            Bits::Int32 => self.int32 += 1,
+            Bits::Int64 => self.int64 += 1,
+        }
+    }
+}
+
+/// Detects the format of the file times and whether the paths are prefixed by the
+/// basename of the archive like Grand Ages: Rome does.
+pub(crate) fn detect_filedates(
+    buf: &[u8],
+    root_names: &[std::ffi::OsString],
+) -> (Option<FileDateFormat>, bool) {
+    let mut fmt = None;
+    let mut prefixed = None;
+
+    for line in String::from_utf8_lossy(buf).lines() {
+        let entry: Vec<_> = line.rsplitn(2, '=').collect();
+        if entry.len() != 2 {
+            continue;
+        }
+        if let Ok(val) = entry[0].parse::<i64>() {
+            if fmt.is_none() && val > 0 {
+                fmt = if val < SHORT_FILEDATES_LIMIT {
+                    Some(FileDateFormat::Short)
+                } else {
+                    Some(FileDateFormat::Default)
+                };
+            }
+            if let Some(Component::Normal(first)) = Path::new(entry[1]).components().next() {
+                let found = root_names.iter().any(|n| n == first);
+                prefixed = Some(prefixed.unwrap_or(true) && !found);
+            }
+        }
+    }
+    (fmt, prefixed.unwrap_or(false))
+}
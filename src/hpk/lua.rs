@@ -33,34 +33,89 @@ static LUA_INVALID_HEADER_64: [u8; 31] = [
     0x78, 0x56, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
     0x00, 0x00, 0x00, 0x00, 0x00, 0x28, 0x77, 0x40,
 ];
+/// Length of the longest bytecode header recognized by [`inspect`], the valid
+/// header of 64-bit Lua 5.3
+pub const MAX_HEADER_LEN: usize = 33;
 const LUA_SIG: &[u8] = b"\x1BLua";
+const LUA_VERSION51_FMT: &[u8] = b"\x51\x00";
+const LUA_VERSION52_FMT: &[u8] = b"\x52\x00";
 const LUA_VERSION53_FMT: &[u8] = b"\x53\x00";
 const LUAC_DATA: &[u8] = b"\x19\x93\r\n\x1A\n";
 const LUAC_INT32: u32 = 0x5678;
 const LUAC_INT64: u64 = 0x5678;
 const LUAC_NUM: f64 = 370.5;
 
-mod parser {
+pub mod parser {
     use super::*;
     use nom::branch::alt;
     use nom::bytes::streaming::{tag, take};
     use nom::combinator::{value, verify};
-    use nom::number::streaming::{le_f64, le_u32, le_u64};
+    use nom::number::streaming::{le_f64, le_u32, le_u64, u8 as byte};
     use nom::sequence::terminated;
     use nom::*;
 
-    #[derive(Debug, Clone, PartialEq, Eq)]
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub enum Bits {
         Int32,
         Int64,
     }
 
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum LuaVersion {
+        Lua51,
+        Lua52,
+        Lua53,
+    }
+
+    /// Bytecode header of a compiled Lua chunk
+    ///
+    /// The sizes of a crippled Lua 5.3 header are the ones restored by [`fix_header`].
+    ///
+    /// [`fix_header`]: super::fix_header
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub struct LuaHeader {
+        pub version: LuaVersion,
+        pub little_endian: bool,
+        pub sizeof_int: u8,
+        pub sizeof_size_t: u8,
+        pub sizeof_instruction: u8,
+        /// Size of `lua_Integer`, only stored by Lua 5.3
+        pub sizeof_integer: Option<u8>,
+        pub sizeof_number: u8,
+        /// `lua_Number` is an integral type, only stored by Lua 5.1 and 5.2
+        pub integral: bool,
+        /// Header is missing the two bytes stripped by Victor Vran and Surviving Mars
+        pub crippled: bool,
+    }
+
+    impl LuaHeader {
+        /// Lua 5.3 is 64-bit if `lua_Integer` is, Lua 5.1 and 5.2 if `size_t` is.
+        pub fn bits(&self) -> Bits {
+            let size = self.sizeof_integer.unwrap_or(self.sizeof_size_t);
+            if size == 8 {
+                Bits::Int64
+            } else {
+                Bits::Int32
+            }
+        }
+    }
+
     fn lua_sig(i: &[u8]) -> IResult<&[u8], &[u8]> {
         tag(LUA_SIG)(i)
     }
+    fn lua_version51_fmt(i: &[u8]) -> IResult<&[u8], &[u8]> {
+        tag(LUA_VERSION51_FMT)(i)
+    }
+    fn lua_version52_fmt(i: &[u8]) -> IResult<&[u8], &[u8]> {
+        tag(LUA_VERSION52_FMT)(i)
+    }
     fn lua_version53_fmt(i: &[u8]) -> IResult<&[u8], &[u8]> {
         tag(LUA_VERSION53_FMT)(i)
     }
+    fn luac_flag(i: &[u8]) -> IResult<&[u8], bool> {
+        let (i, val) = verify(byte, |val| *val <= 1)(i)?;
+        Ok((i, val == 1))
+    }
     fn luac_data(i: &[u8]) -> IResult<&[u8], &[u8]> {
         tag(LUAC_DATA)(i)
     }
@@ -91,6 +146,90 @@ mod parser {
         ))(i)
     }
 
+    /// Lua 5.1 and 5.2 share the header layout, 5.2 adds the `LUAC_TAIL` bytes.
+    fn lua5x_header(version: LuaVersion, i: &[u8]) -> IResult<&[u8], LuaHeader> {
+        let (i, little_endian) = luac_flag(i)?;
+        let (i, sizeof_int) = byte(i)?;
+        let (i, sizeof_size_t) = byte(i)?;
+        let (i, sizeof_instruction) = byte(i)?;
+        let (i, sizeof_number) = byte(i)?;
+        let (i, integral) = luac_flag(i)?;
+        let hdr = LuaHeader {
+            version,
+            little_endian,
+            sizeof_int,
+            sizeof_size_t,
+            sizeof_instruction,
+            sizeof_integer: None,
+            sizeof_number,
+            integral,
+            crippled: false,
+        };
+        Ok((i, hdr))
+    }
+
+    fn lua51_header(i: &[u8]) -> IResult<&[u8], LuaHeader> {
+        let (i, _) = lua_sig(i)?;
+        let (i, _) = lua_version51_fmt(i)?;
+        lua5x_header(LuaVersion::Lua51, i)
+    }
+
+    fn lua52_header(i: &[u8]) -> IResult<&[u8], LuaHeader> {
+        let (i, _) = lua_sig(i)?;
+        let (i, _) = lua_version52_fmt(i)?;
+        terminated(|i| lua5x_header(LuaVersion::Lua52, i), luac_data)(i)
+    }
+
+    fn lua53_header(i: &[u8]) -> IResult<&[u8], LuaHeader> {
+        let (i, _) = lua_sig(i)?;
+        let (i, _) = lua_version53_fmt(i)?;
+        let (i, _) = luac_data(i)?;
+        let (i, sizes) = luac_valid_sizeof(i)?;
+        let (i, _) = alt((
+            terminated(luac_int32, luac_num),
+            terminated(luac_int64, luac_num),
+        ))(i)?;
+        let hdr = LuaHeader {
+            version: LuaVersion::Lua53,
+            little_endian: true,
+            sizeof_int: sizes[0],
+            sizeof_size_t: sizes[1],
+            sizeof_instruction: sizes[2],
+            sizeof_integer: Some(sizes[3]),
+            sizeof_number: sizes[4],
+            integral: false,
+            crippled: false,
+        };
+        Ok((i, hdr))
+    }
+
+    fn lua53_crippled_header(i: &[u8]) -> IResult<&[u8], LuaHeader> {
+        let (i, bits) = check_invalid_header(i)?;
+        let hdr = LuaHeader {
+            version: LuaVersion::Lua53,
+            little_endian: true,
+            sizeof_int: 4,
+            sizeof_size_t: 4,
+            sizeof_instruction: 4,
+            sizeof_integer: Some(if bits == Bits::Int64 { 8 } else { 4 }),
+            sizeof_number: 8,
+            integral: false,
+            crippled: true,
+        };
+        Ok((i, hdr))
+    }
+
+    /// Parses the bytecode header of Lua 5.1, 5.2 and 5.3 including the crippled
+    /// header of Victor Vran and Surviving Mars.
+    pub fn header(i: &[u8]) -> IResult<&[u8], LuaHeader> {
+        alt((
+            lua51_header,
+            lua52_header,
+            lua53_header,
+            lua53_crippled_header,
+        ))(i)
+    }
+
     pub fn check_valid_header(i: &[u8]) -> IResult<&[u8], Bits> {
         let (i, _) = lua_sig(i)?;
         let (i, _) = lua_version53_fmt(i)?;
@@ -103,110 +242,187 @@ mod parser {
     }
 }
 
-pub struct LuaHeaderRewriter<T, F> {
-    inner: T,
-    done: bool,
-    func: F,
+/// Decision about the bytes buffered from the start of a Lua file
+enum Rewrite {
+    /// More bytes are required to recognize the header
+    Incomplete,
+    /// Replace the first `n` bytes with the header
+    Replace(usize, &'static [u8]),
+    Keep,
+}
+
+/// Reader that rewrites the bytecode header of a Lua file
+///
+/// The first bytes are buffered until the header is recognized or rejected,
+/// independent of how many bytes a single `read` of the inner reader returns.
+pub struct LuaHeaderReader<R> {
+    inner: R,
+    header: Option<Vec<u8>>,
+    pending: io::Cursor<Vec<u8>>,
+    rewrite: fn(&[u8]) -> Rewrite,
 }
 
-impl<T, F> LuaHeaderRewriter<T, F> {
-    fn new(inner: T, func: F) -> Self {
-        Self {
-            inner,
-            done: false,
-            func,
+impl<R: Read> Read for LuaHeaderReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while let Some(header) = self.header.as_mut() {
+            let mut tmp = [0; 64];
+            let n = self.inner.read(&mut tmp)?;
+            header.extend_from_slice(&tmp[..n]);
+
+            let data = match (self.rewrite)(header) {
+                Rewrite::Incomplete if n > 0 => continue,
+                Rewrite::Replace(n, hdr) => [hdr, &header[n..]].concat(),
+                Rewrite::Incomplete | Rewrite::Keep => std::mem::take(header),
+            };
+            self.pending = io::Cursor::new(data);
+            self.header = None;
         }
+        if (self.pending.position() as usize) < self.pending.get_ref().len() {
+            return self.pending.read(buf);
+        }
+        self.inner.read(buf)
     }
 }
 
-impl<R, F> Read for LuaHeaderRewriter<R, F>
-where
-    R: Read,
-    F: Fn(&mut R, &mut [u8]) -> io::Result<usize>,
-{
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.done {
-            self.inner.read(buf)
-        } else {
-            let res = (self.func)(&mut self.inner, buf);
-            self.done = true;
-            res
+/// Writer that rewrites the bytecode header of a Lua file
+///
+/// The first bytes are buffered until the header is recognized or rejected.
+/// Bytes of a file shorter than the header are written unmodified on `flush` or drop.
+pub struct LuaHeaderWriter<W: Write> {
+    inner: W,
+    header: Option<Vec<u8>>,
+    rewritten: bool,
+    rewrite: fn(&[u8]) -> Rewrite,
+}
+
+impl<W: Write> LuaHeaderWriter<W> {
+    /// Returns `true` if the header has been rewritten. The decision is final after `flush`.
+    pub fn is_rewritten(&self) -> bool {
+        self.rewritten
+    }
+
+    fn write_header(&mut self, eof: bool) -> io::Result<()> {
+        if let Some(header) = self.header.as_ref() {
+            match (self.rewrite)(header) {
+                Rewrite::Incomplete if !eof => return Ok(()),
+                Rewrite::Replace(n, hdr) => {
+                    self.inner.write_all(hdr)?;
+                    self.inner.write_all(&header[n..])?;
+                    self.rewritten = true;
+                }
+                Rewrite::Incomplete | Rewrite::Keep => self.inner.write_all(header)?,
+            }
+            self.header = None;
         }
+        Ok(())
     }
 }
 
-impl<W, F> Write for LuaHeaderRewriter<W, F>
-where
-    W: Write,
-    F: Fn(&mut W, &[u8]) -> io::Result<usize>,
-{
+impl<W: Write> Write for LuaHeaderWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if self.done {
-            self.inner.write(buf)
-        } else {
-            let res = (self.func)(&mut self.inner, buf);
-            self.done = true;
-            res
+        match self.header.as_mut() {
+            Some(header) => {
+                header.extend_from_slice(buf);
+                self.write_header(false)?;
+                Ok(buf.len())
+            }
+            None => self.inner.write(buf),
         }
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        self.write_header(true)?;
         self.inner.flush()
     }
 }
 
-pub fn cripple_header<R>(r: R) -> impl Read
+impl<W: Write> Drop for LuaHeaderWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.write_header(true);
+    }
+}
+
+/// Wraps a reader and removes the two bytes of a valid Lua 5.3 bytecode header
+/// like Victor Vran and Surviving Mars expect it
+pub fn cripple_header<R>(r: R) -> LuaHeaderReader<R>
 where
     R: Read,
 {
-    LuaHeaderRewriter::new(r, read_with_invalid_header)
+    LuaHeaderReader {
+        inner: r,
+        header: Some(vec![]),
+        pending: io::Cursor::new(vec![]),
+        rewrite: rewrite_valid_header,
+    }
 }
 
-pub fn fix_header<W>(w: W) -> impl Write
+/// Wraps a writer and restores the crippled Lua 5.3 bytecode header of
+/// Victor Vran and Surviving Mars scripts
+pub fn fix_header<W>(w: W) -> LuaHeaderWriter<W>
 where
     W: Write,
 {
-    LuaHeaderRewriter::new(w, write_with_valid_header)
+    LuaHeaderWriter {
+        inner: w,
+        header: Some(vec![]),
+        rewritten: false,
+        rewrite: rewrite_invalid_header,
+    }
 }
 
-fn read_with_invalid_header<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
-    let mut tmp = vec![0; buf.len()];
-    match r.read(&mut tmp) {
-        Ok(0) => Ok(0),
-        Ok(n) => {
-            let mut tmp = &tmp[0..n];
-            match parser::check_valid_header(tmp) {
-                Ok((remaining, bits)) => {
-                    let mut w = io::Cursor::new(buf);
-                    let mut n = 0;
-                    let hdr = match bits {
-                        parser::Bits::Int32 => &LUA_INVALID_HEADER_32[..],
-                        parser::Bits::Int64 => &LUA_INVALID_HEADER_64[..],
-                    };
-                    n += w.write(hdr)?;
-                    n += w.write(remaining)?;
-                    Ok(n)
-                }
-                Err(_) => tmp.read(buf),
-            }
+fn rewrite_valid_header(buf: &[u8]) -> Rewrite {
+    match parser::check_valid_header(buf) {
+        Ok((remaining, bits)) if buf.starts_with(valid_header(bits)) => {
+            Rewrite::Replace(buf.len() - remaining.len(), invalid_header(bits))
         }
-        Err(e) => Err(e),
+        Err(nom::Err::Incomplete(_)) => Rewrite::Incomplete,
+        _ => Rewrite::Keep,
     }
 }
 
-fn write_with_valid_header<W: Write>(w: &mut W, buf: &[u8]) -> io::Result<usize> {
+fn rewrite_invalid_header(buf: &[u8]) -> Rewrite {
     match parser::check_invalid_header(buf) {
-        Ok((remaining, bits)) => {
-            let mut n = 0;
-            let hdr = match bits {
-                parser::Bits::Int32 => &LUA_VALID_HEADER_32[..],
-                parser::Bits::Int64 => &LUA_VALID_HEADER_64[..],
-            };
-            n += w.write(hdr)? - 2; // ignore the two additional bytes
-            n += w.write(remaining)?;
-            Ok(n)
+        Ok((remaining, bits)) if buf.starts_with(invalid_header(bits)) => {
+            Rewrite::Replace(buf.len() - remaining.len(), valid_header(bits))
         }
-        Err(_) => w.write(buf),
+        Err(nom::Err::Incomplete(_)) => Rewrite::Incomplete,
+        _ => Rewrite::Keep,
+    }
+}
+
+// Only the Lua 5.3 headers of Victor Vran and Surviving Mars are rewritten.
+// Bytecode of other Lua versions or with other sizes is passed through unmodified.
+fn valid_header(bits: parser::Bits) -> &'static [u8] {
+    match bits {
+        parser::Bits::Int32 => &LUA_VALID_HEADER_32[..],
+        parser::Bits::Int64 => &LUA_VALID_HEADER_64[..],
+    }
+}
+
+fn invalid_header(bits: parser::Bits) -> &'static [u8] {
+    match bits {
+        parser::Bits::Int32 => &LUA_INVALID_HEADER_32[..],
+        parser::Bits::Int64 => &LUA_INVALID_HEADER_64[..],
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LuaScript {
+    Source,
+    Bytecode(parser::LuaHeader),
+    /// Bytecode with a header in an unknown format
+    Unknown,
+}
+
+/// Classifies the content of a Lua file by its bytecode header
+pub fn inspect(buf: &[u8]) -> LuaScript {
+    // Precompiled chunks start with the escape character of `LUA_SIGNATURE`
+    if !buf.starts_with(&LUA_SIG[..1]) {
+        return LuaScript::Source;
+    }
+    match parser::header(buf) {
+        Ok((_, hdr)) => LuaScript::Bytecode(hdr),
+        Err(_) => LuaScript::Unknown,
     }
 }
 
@@ -227,6 +443,18 @@ mod tests {
         );
     }
 
+    #[test]
+    fn max_header_len() {
+        let headers = [
+            &LUA_VALID_HEADER_32[..],
+            &LUA_VALID_HEADER_64,
+            &LUA_INVALID_HEADER_32,
+            &LUA_INVALID_HEADER_64,
+        ];
+        let max = headers.iter().map(|h| h.len()).max();
+        assert_eq!(max, Some(MAX_HEADER_LEN));
+    }
+
     #[test]
     fn check_invalid_header_parser() {
         assert_eq!(
@@ -239,6 +467,58 @@ mod tests {
         );
     }
 
+    #[rustfmt::skip]
+    static LUA51_HEADER: [u8; 12] = [
+        0x1B, 0x4C, 0x75, 0x61, 0x51, 0x00,
+        0x01, 0x04, 0x08, 0x04, 0x08, 0x00,
+    ];
+    #[rustfmt::skip]
+    static LUA52_HEADER: [u8; 18] = [
+        0x1B, 0x4C, 0x75, 0x61, 0x52, 0x00,
+        0x01, 0x04, 0x04, 0x04, 0x08, 0x00,
+        0x19, 0x93, 0x0D, 0x0A, 0x1A, 0x0A,
+    ];
+
+    #[test]
+    fn header_parser() {
+        let (_, hdr) = parser::header(&LUA51_HEADER).unwrap();
+        assert_eq!(hdr.version, parser::LuaVersion::Lua51);
+        assert!(hdr.little_endian);
+        assert_eq!(hdr.sizeof_int, 4);
+        assert_eq!(hdr.sizeof_size_t, 8);
+        assert_eq!(hdr.sizeof_instruction, 4);
+        assert_eq!(hdr.sizeof_number, 8);
+        assert_eq!(hdr.bits(), parser::Bits::Int64);
+        assert!(!hdr.crippled);
+
+        let (_, hdr) = parser::header(&LUA52_HEADER).unwrap();
+        assert_eq!(hdr.version, parser::LuaVersion::Lua52);
+        assert_eq!(hdr.bits(), parser::Bits::Int32);
+
+        let (_, hdr) = parser::header(&LUA_VALID_HEADER_64).unwrap();
+        assert_eq!(hdr.version, parser::LuaVersion::Lua53);
+        assert_eq!(hdr.sizeof_integer, Some(8));
+        assert!(!hdr.crippled);
+
+        let (_, hdr) = parser::header(&LUA_INVALID_HEADER_32).unwrap();
+        assert_eq!(hdr.version, parser::LuaVersion::Lua53);
+        assert_eq!(hdr.bits(), parser::Bits::Int32);
+        assert!(hdr.crippled);
+
+        assert!(parser::header(b"-- lua source code\n").is_err());
+    }
+
+    #[test]
+    fn inspect_script() {
+        assert_eq!(inspect(b"print('Hello World')"), LuaScript::Source);
+        assert_eq!(inspect(b""), LuaScript::Source);
+        assert_eq!(inspect(b"\x1BLua\x54\x00"), LuaScript::Unknown);
+        match inspect(&LUA_INVALID_HEADER_64) {
+            LuaScript::Bytecode(hdr) => assert!(hdr.crippled),
+            script => panic!("unexpected {:?}", script),
+        }
+    }
+
     #[test]
     fn header_rewrite() {
         let mut input = LUA_VALID_HEADER_64.to_vec();
@@ -261,13 +541,77 @@ mod tests {
             let mut wrapper = fix_header(&mut output);
             let n = io::copy(&mut buf, &mut wrapper).unwrap();
 
-            // LuaHeaderRewriter has to lie here about the written bytes
+            // LuaHeaderWriter reports the bytes written by the caller
             assert_eq!(n, LUA_INVALID_HEADER_64.len() as u64 + 4);
+            assert!(wrapper.is_rewritten());
         }
         assert_eq!(output.position(), LUA_VALID_HEADER_64.len() as u64 + 4);
 
         assert_eq!(input.into_inner(), output.into_inner());
     }
+
+    /// Reader returning a single byte per `read` call
+    struct ByteReader<'a>(&'a [u8]);
+
+    impl Read for ByteReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.split_first() {
+                Some((b, rest)) if !buf.is_empty() => {
+                    buf[0] = *b;
+                    self.0 = rest;
+                    Ok(1)
+                }
+                _ => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn header_rewrite_byte_by_byte() {
+        for (valid, invalid) in [
+            (&LUA_VALID_HEADER_32[..], &LUA_INVALID_HEADER_32[..]),
+            (&LUA_VALID_HEADER_64[..], &LUA_INVALID_HEADER_64[..]),
+        ] {
+            let input = [valid, &[0xCA, 0xFE, 0xCA, 0xFE]].concat();
+            let expected = [invalid, &[0xCA, 0xFE, 0xCA, 0xFE]].concat();
+
+            let mut crippled = vec![];
+            let mut r = cripple_header(ByteReader(&input));
+            let mut byte = [0; 1];
+            while r.read(&mut byte).unwrap() > 0 {
+                crippled.push(byte[0]);
+            }
+            assert_eq!(crippled, expected);
+
+            let mut fixed = vec![];
+            {
+                let mut w = fix_header(&mut fixed);
+                for b in &crippled {
+                    w.write_all(std::slice::from_ref(b)).unwrap();
+                }
+                w.flush().unwrap();
+            }
+            assert_eq!(fixed, input);
+        }
+    }
+
+    #[test]
+    fn header_rewrite_short_input() {
+        let input = &LUA_INVALID_HEADER_64[..10];
+
+        let mut output = vec![];
+        {
+            let mut w = fix_header(&mut output);
+            w.write_all(input).unwrap();
+            w.flush().unwrap();
+            assert!(!w.is_rewritten());
+        }
+        assert_eq!(output, input);
+
+        let mut output = vec![];
+        io::copy(&mut cripple_header(ByteReader(input)), &mut output).unwrap();
+        assert_eq!(output, input);
+    }
 }
 // }}}
 
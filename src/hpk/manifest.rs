@@ -0,0 +1,151 @@
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Compression, FileDateFormat, HpkResult, NameEncoding};
+
+/// File name of the manifest in the destination folder of an extraction
+pub const MANIFEST_NAME: &str = ".hpk-manifest.json";
+
+const MANIFEST_VERSION: u32 = 1;
+
+/// Metadata of an extracted archive for a lossless repack
+///
+/// The entries are recorded in the order of the directory listings of the archive.
+/// See [`ExtractOptions::write_manifest`] and [`CreateOptions::with_manifest`].
+///
+/// [`ExtractOptions::write_manifest`]: crate::ExtractOptions::write_manifest
+/// [`CreateOptions::with_manifest`]: crate::CreateOptions::with_manifest
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    /// Encoder and chunk size of the whole archive
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<ChunkedCompression>,
+    #[serde(default)]
+    pub name_encoding: NameEncoding,
+    /// Content of a processed `_filedates` file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filedates: Option<Filedates>,
+    pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkedCompression {
+    pub codec: Compression,
+    pub chunk_size: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Filedates {
+    pub format: FileDateFormat,
+    /// Lines of the file without the line feed
+    pub lines: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Path of the entry with `/` as separator
+    pub path: String,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub dir: bool,
+    /// Encoder and chunk size of a compressed file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compression: Option<ChunkedCompression>,
+    /// The Lua bytecode header was fixed on extraction and is crippled again on creation
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub lua_fixed: bool,
+}
+
+fn is_false(value: &bool) -> bool {
+    !value
+}
+
+impl Manifest {
+    pub(crate) fn new(name_encoding: NameEncoding) -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            compression: None,
+            name_encoding,
+            filedates: None,
+            entries: vec![],
+        }
+    }
+
+    pub fn read_from<P: AsRef<Path>>(file: P) -> HpkResult<Manifest> {
+        let r = io::BufReader::new(File::open(file)?);
+        let manifest: Manifest = serde_json::from_reader(r).map_err(io::Error::from)?;
+        if manifest.version != MANIFEST_VERSION {
+            let msg = format!("unsupported manifest version: {}", manifest.version);
+            return Err(io::Error::new(io::ErrorKind::InvalidData, msg).into());
+        }
+        Ok(manifest)
+    }
+
+    pub fn write_to<P: AsRef<Path>>(&self, file: P) -> HpkResult<()> {
+        let mut w = io::BufWriter::new(File::create(file)?);
+        serde_json::to_writer_pretty(&mut w, self).map_err(io::Error::from)?;
+        writeln!(w)?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+impl Filedates {
+    pub(crate) fn new(encoding: NameEncoding, buf: &[u8]) -> Self {
+        let (format, _) = crate::info::detect_filedates(buf, &[]);
+        let buf = buf.strip_suffix(b"\n").unwrap_or(buf);
+        let lines = buf
+            .split(|b| *b == b'\n')
+            .map(|line| match encoding.decode(line) {
+                Some(line) => line.into_owned(),
+                None => String::from_utf8_lossy(line).into_owned(),
+            })
+            .collect();
+        Filedates {
+            format: format.unwrap_or(FileDateFormat::Default),
+            lines,
+        }
+    }
+
+    /// Encodes the lines for the `_filedates` file
+    pub(crate) fn to_bytes(&self, encoding: NameEncoding) -> Vec<u8> {
+        let mut buf = vec![];
+        for line in &self.lines {
+            match encoding.encode(line.as_ref()) {
+                Some(line) => buf.extend_from_slice(&line),
+                None => buf.extend_from_slice(line.as_bytes()),
+            }
+            buf.push(b'\n');
+        }
+        buf
+    }
+}
+
+impl ManifestEntry {
+    pub(crate) fn new(path: &Path, dir: bool) -> Self {
+        let path = path
+            .components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/");
+        ManifestEntry {
+            path,
+            dir,
+            compression: None,
+            lua_fixed: false,
+        }
+    }
+
+    /// Returns the path of the entry if it only consists of normal components
+    pub fn path(&self) -> Option<PathBuf> {
+        let path: PathBuf = self.path.split('/').collect();
+        let normal = path
+            .components()
+            .all(|c| std::matches!(c, Component::Normal(_)));
+        (normal && !self.path.is_empty()).then_some(path)
+    }
+}
@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::info::detect_filedates;
+use crate::overlay::Overlay;
+use crate::write::write_archive;
+use crate::{FileDateFormat, HpkError, HpkResult, NameEncoding};
+
+/// Which file is kept if several archives contain the same path
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Conflict {
+    /// The file of the later archive wins like the games load mods
+    #[default]
+    LastWins,
+    FirstWins,
+    /// Fails with [`HpkError::MergeConflict`]
+    Error,
+}
+
+#[derive(Default)]
+pub struct MergeOptions {
+    conflict: Conflict,
+    name_encoding: NameEncoding,
+    verbose: bool,
+}
+
+impl MergeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_conflict(&mut self, conflict: Conflict) {
+        self.conflict = conflict;
+    }
+
+    pub fn set_name_encoding(&mut self, encoding: NameEncoding) {
+        self.name_encoding = encoding;
+    }
+
+    /// Lists every merged file and the archive it comes from
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+}
+
+/// Merges the archives into a new archive
+///
+/// The member data is copied as it's stored, compressed files are not recompressed.
+/// The lines of the `_filedates` files are taken from the archive whose file is kept
+/// and converted to the format of the first archive with a `_filedates` file.
+/// A file that is a directory in another archive always fails with
+/// [`HpkError::MergeConflict`].
+///
+/// The archive is written to a temp file next to the output file like [`create`] does,
+/// so the output can be one of the input archives.
+///
+/// [`create`]: crate::create
+pub fn merge<P: AsRef<Path>>(options: &MergeOptions, inputs: &[P], file: P) -> HpkResult<()> {
+    let mut overlay = Overlay::new();
+    overlay.set_name_encoding(options.name_encoding);
+    let mut layers: Vec<_> = inputs.iter().collect();
+    if options.conflict == Conflict::FirstWins {
+        layers.reverse();
+    }
+    for input in layers {
+        overlay.add_archive(input)?;
+    }
+
+    for entry in overlay.entries() {
+        let conflict = options.conflict == Conflict::Error && !entry.overrides().is_empty();
+        if conflict || overlay.dirs().contains(entry.path()) {
+            return Err(HpkError::MergeConflict(entry.path().to_path_buf()));
+        }
+    }
+    let filedates = merge_filedates(
+        &overlay,
+        options.name_encoding,
+        inputs.len(),
+        options.conflict,
+        |_, _| true,
+    )?;
+
+    let files = overlay.entries().map(|e| e.path());
+    let dirs = overlay.dirs().iter().map(|p| p.as_path());
+    write_archive(
+        file.as_ref(),
+        options.name_encoding,
+        files,
+        dirs,
+        filedates.as_deref(),
+        |writer, path| {
+            let entry = overlay.get(path).expect("merged file");
+            overlay.read_file(entry, |r| {
+                writer.add_file(path, r)?;
+                Ok(())
+            })?;
+            if options.verbose {
+                let layer = overlay.layer_path(entry.layer());
+                println!("{} ({})", path.display(), layer.display());
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Merges the lines of the `_filedates` files
+///
+/// The line of a file comes from the layer whose file is kept. The lines of other
+/// paths, e.g. directories, come from the last layer with the path. `keep` filters
+/// the lines by the layer and the path, lines whose path can't be decoded are dropped.
+pub(crate) fn merge_filedates<F>(
+    overlay: &Overlay,
+    encoding: NameEncoding,
+    layers: usize,
+    conflict: Conflict,
+    keep: F,
+) -> HpkResult<Option<Vec<u8>>>
+where
+    F: Fn(usize, &Path) -> bool,
+{
+    let mut files = vec![];
+    for layer in 0..layers {
+        files.push(overlay.read_filedates(layer)?);
+    }
+    // the format of the first input archive with a `_filedates` file
+    let formats = files.iter().map(|f| f.as_deref().map(detect_format));
+    let format = if conflict == Conflict::FirstWins {
+        formats.rev().flatten().next()
+    } else {
+        formats.flatten().next()
+    };
+    let Some(format) = format else {
+        return Ok(None);
+    };
+
+    let mut lines: Vec<Vec<u8>> = vec![];
+    let mut index: HashMap<Vec<u8>, usize> = HashMap::new();
+    for (layer, buf) in files.iter().enumerate() {
+        let Some(buf) = buf else { continue };
+        let layer_format = detect_format(buf);
+        for line in buf.split(|b| *b == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let Some(pos) = line.iter().rposition(|b| *b == b'=') else {
+                continue;
+            };
+            let (path, value) = (&line[..pos], &line[pos + 1..]);
+            let Some(entry_path) = encoding.decode(path) else {
+                continue;
+            };
+            let entry_path = entry_path.replace('\\', "/");
+            let entry_path = Path::new(&entry_path);
+            if !keep(layer, entry_path) {
+                continue;
+            }
+            // the line of a file comes from the layer whose file is kept
+            if let Some(entry) = overlay.get(entry_path) {
+                if entry.layer() != layer {
+                    continue;
+                }
+            }
+            let mut line = path.to_vec();
+            line.push(b'=');
+            line.extend_from_slice(&convert_filedate(value, layer_format, format));
+            match index.get(path) {
+                Some(&i) => lines[i] = line,
+                None => {
+                    index.insert(path.to_vec(), lines.len());
+                    lines.push(line);
+                }
+            }
+        }
+    }
+
+    let mut buf = vec![];
+    for line in lines {
+        buf.extend_from_slice(&line);
+        buf.push(b'\n');
+    }
+    Ok(Some(buf))
+}
+
+fn detect_format(buf: &[u8]) -> FileDateFormat {
+    detect_filedates(buf, &[])
+        .0
+        .unwrap_or(FileDateFormat::Default)
+}
+
+fn convert_filedate(value: &[u8], from: FileDateFormat, to: FileDateFormat) -> Vec<u8> {
+    let parsed = std::str::from_utf8(value)
+        .ok()
+        .and_then(|v| v.parse::<i64>().ok());
+    let converted = match (parsed, from, to) {
+        (Some(v), FileDateFormat::Default, FileDateFormat::Short) => v / 2000,
+        (Some(v), FileDateFormat::Short, FileDateFormat::Default) => v.saturating_mul(2000),
+        _ => return value.to_vec(),
+    };
+    converted.to_string().into_bytes()
+}
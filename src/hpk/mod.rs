@@ -1,22 +1,60 @@
+use std::collections::HashMap;
 use std::ffi::OsStr;
+use std::fmt;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
 use std::io::Cursor;
 use std::io::SeekFrom;
-use std::path::{Path, PathBuf};
+use std::ops::ControlFlow;
+use std::path::{Component, Path, PathBuf};
 use std::str;
 
 use byteorder::{ReadBytesExt, WriteBytesExt, LE};
 use glob::Pattern;
+use serde::{Deserialize, Serialize};
 
+use crate::atomic::AtomicFile;
+use crate::manifest::{ChunkedCompression, ManifestEntry};
+use crate::progress::{HookReader, HookWriter, NoHooks, ProgressFn};
+use crate::write::{changed_input, ChunkTable, ChunkTables, ChunkWriter, SizeCounter};
+use crate::write::{Compressed, KEPT_DATA_LIMIT};
+
+mod atomic;
 pub mod compress;
-mod lua;
+mod copy_members;
+mod encoding;
+#[doc(hidden)]
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
+mod game;
+mod info;
+pub mod lua;
+pub mod manifest;
+mod merge;
+mod overlay;
+mod progress;
 mod read;
+mod recompress;
+mod split;
 mod walk;
-
+mod write;
+
+pub use crate::copy_members::{copy_members, CopyOptions};
+pub use crate::encoding::NameEncoding;
+pub use crate::game::Game;
+pub use crate::info::{info, ArchiveInfo, LuaStats};
+pub use crate::lua::parser::{Bits, LuaHeader, LuaVersion};
+pub use crate::lua::{inspect as inspect_lua, LuaScript};
+pub use crate::manifest::{Manifest, MANIFEST_NAME};
+pub use crate::merge::{merge, Conflict, MergeOptions};
+pub use crate::overlay::{Overlay, OverlayEntry};
+pub use crate::progress::{Hooks, Progress, ProgressEntry, ProgressStage};
 pub use crate::read::FragmentedReader;
+pub use crate::recompress::{recompress, RecompressOptions};
+pub use crate::split::{split, SplitOptions};
 pub use crate::walk::{walk, HpkIter};
+pub use crate::write::HpkWriter;
 
 const HPK_SIG: [u8; 4] = *b"BPUL";
 const HEADER_LENGTH: u8 = 36;
@@ -32,13 +70,39 @@ type HpkResult<T> = Result<T, HpkError>;
 pub enum HpkError {
     InvalidHeader,
     InvalidDirEntryName,
+    /// The name would place the entry outside of its parent directory
+    UnsafeDirEntryName(PathBuf),
     InvalidFragmentIndex,
+    /// The fragment exceeds the size of the archive
+    InvalidFragment,
+    InvalidCompressionHeader,
+    /// The directory entry points to one of its parent directories
+    CyclicDirEntry(PathBuf),
+    /// The directory entry points to the entries of a directory that was already traversed
+    DuplicateDirEntry(PathBuf),
+    /// The operation was cancelled by one of the [`Hooks`]
+    Cancelled,
+    /// An offset or length exceeds the 32-bit fields of the format, which limits
+    /// archives and compressed data to 4 GiB
+    TooLarge,
+    /// The file exists in several archives merged with [`Conflict::Error`] or it's a
+    /// directory in one of the other archives
+    MergeConflict(PathBuf),
+    /// Several parts of [`split`] would be written to the same archive, e.g. the files
+    /// of the root directory and the top-level directory named like the input archive
+    SplitConflict(PathBuf),
     Io(io::Error),
     WalkDir(walkdir::Error),
 }
 
 impl From<io::Error> for HpkError {
     fn from(err: io::Error) -> HpkError {
+        if err.get_ref().is_some_and(|e| e.is::<progress::Cancelled>()) {
+            return HpkError::Cancelled;
+        }
+        if err.get_ref().is_some_and(|e| e.is::<write::TooLarge>()) {
+            return HpkError::TooLarge;
+        }
         HpkError::Io(err)
     }
 }
@@ -100,20 +164,21 @@ impl Header {
         w.write_u32::<LE>(self.data_offset)?;
         w.write_u32::<LE>(self.fragments_per_file)?;
         w.write_u32::<LE>(self._unknown2)?;
-        w.write_u32::<LE>(self.fragments_residual_offset as u32)?;
-        w.write_u32::<LE>(self.fragments_residual_count as u32)?;
+        w.write_u32::<LE>(checked_u32(self.fragments_residual_offset)?)?;
+        w.write_u32::<LE>(checked_u32(self.fragments_residual_count)?)?;
         w.write_u32::<LE>(self._unknown5)?;
-        w.write_u32::<LE>(self.fragmented_filesystem_offset as u32)?;
-        w.write_u32::<LE>(self.fragmented_filesystem_length as u32)?;
+        w.write_u32::<LE>(checked_u32(self.fragmented_filesystem_offset)?)?;
+        w.write_u32::<LE>(checked_u32(self.fragmented_filesystem_length)?)?;
 
         Ok(())
     }
 
     pub fn filesystem_entries(&self) -> usize {
         // The size, in bytes, of how `Fragment` appears in files.
-        const FRAGMENT_SIZE: u32 = 8;
-        (self.fragmented_filesystem_length as u32 / (FRAGMENT_SIZE * self.fragments_per_file))
-            as usize
+        const FRAGMENT_SIZE: u64 = 8;
+        self.fragmented_filesystem_length
+            .checked_div(FRAGMENT_SIZE * u64::from(self.fragments_per_file))
+            .unwrap_or(0) as usize
     }
 }
 
@@ -143,8 +208,10 @@ impl Fragment {
     }
 
     fn write(&self, w: &mut dyn Write) -> HpkResult<()> {
-        w.write_u32::<LE>(self.offset as u32)?;
-        w.write_u32::<LE>(self.length as u32)?;
+        // the end of the fragment must be addressable as well
+        checked_u32(self.offset.saturating_add(self.length))?;
+        w.write_u32::<LE>(checked_u32(self.offset)?)?;
+        w.write_u32::<LE>(checked_u32(self.length)?)?;
 
         Ok(())
     }
@@ -228,7 +295,12 @@ impl DirEntry {
         }
     }
 
-    fn read_from<T: Read>(parent: &Path, depth: usize, mut r: T) -> HpkResult<DirEntry> {
+    fn read_from<T: Read>(
+        parent: &Path,
+        depth: usize,
+        encoding: NameEncoding,
+        mut r: T,
+    ) -> HpkResult<DirEntry> {
         let fragment_index = r
             .read_u32::<LE>()?
             .checked_sub(1)
@@ -239,30 +311,55 @@ impl DirEntry {
         let name_length = r.read_u16::<LE>()?;
         let mut buf = vec![0; name_length as usize];
         r.read_exact(&mut buf)?;
-        let name = str::from_utf8(&buf).map_err(|_| HpkError::InvalidDirEntryName)?;
+        let name = encoding.decode(&buf).ok_or(HpkError::InvalidDirEntryName)?;
+
+        if !is_safe_name(&name) {
+            return Err(HpkError::UnsafeDirEntryName(parent.join(&*name)));
+        }
 
         Ok(DirEntry {
-            path: parent.join(name),
+            path: parent.join(&*name),
             kind,
             index: fragment_index as usize,
             depth,
         })
     }
 
-    fn write(&self, w: &mut dyn Write) -> HpkResult<()> {
-        w.write_u32::<LE>(self.index as u32)?;
+    fn write(&self, encoding: NameEncoding, w: &mut dyn Write) -> HpkResult<()> {
+        let index = u32::try_from(self.index).map_err(|_| HpkError::TooLarge)?;
+        w.write_u32::<LE>(index)?;
         w.write_u32::<LE>(self.kind.to_value())?;
         let name = self
             .path
             .file_name()
-            .and_then(OsStr::to_str)
+            .and_then(|name| encoding.encode(name))
             .ok_or(HpkError::InvalidDirEntryName)?;
-        w.write_u16::<LE>(name.len() as u16)?;
-        w.write_all(name.as_bytes())?;
+        let name_length = u16::try_from(name.len()).map_err(|_| HpkError::InvalidDirEntryName)?;
+        w.write_u16::<LE>(name_length)?;
+        w.write_all(&name)?;
         Ok(())
     }
 }
 
+/// Returns [`HpkError::TooLarge`] if the value doesn't fit in a 32-bit field of the format
+fn checked_u32(value: u64) -> HpkResult<u32> {
+    u32::try_from(value).map_err(|_| HpkError::TooLarge)
+}
+
+/// Checks that a name is a single normal path component
+///
+/// Empty names, `.`, `..`, path separators and drive prefixes would allow an
+/// archive to write outside of the destination folder.
+fn is_safe_name(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', ':', '\0'])
+}
+
+/// Checks that a relative path only consists of normal components
+fn is_safe_path(path: &Path) -> bool {
+    path.components()
+        .all(|c| std::matches!(c, Component::Normal(_)))
+}
+
 pub fn get_compression<T: Read + Seek>(r: &mut T) -> HpkResult<Compression> {
     let pos = r.stream_position()?;
     let compression = match Compression::read_from(r) {
@@ -279,9 +376,21 @@ pub fn get_compression<T: Read + Seek>(r: &mut T) -> HpkResult<Compression> {
 /// if no data is written at all the hpk compression header is written without any chunks
 /// it's the same behaviour as in a DLC file for Tropico 4
 ///
+/// The compressed chunks are kept in memory until the offset table is written because
+/// the writer can't seek back. [`create`] writes the chunks directly to the archive.
 pub fn compress(options: &CompressOptions, r: &mut dyn Read, w: &mut dyn Write) -> HpkResult<u64> {
-    use crate::compress::Encoder;
+    compress_with_progress(options, r, w, &|_| ControlFlow::Continue(()))
+}
 
+/// Compresses the data like [`compress()`] and reports the number of read bytes after every chunk
+///
+/// Returning `ControlFlow::Break(())` cancels the compression with [`HpkError::Cancelled`].
+pub fn compress_with_progress(
+    options: &CompressOptions,
+    r: &mut dyn Read,
+    w: &mut dyn Write,
+    report: &dyn Fn(u64) -> ControlFlow<()>,
+) -> HpkResult<u64> {
     let mut inflated_length = 0;
     let mut output_buffer = vec![];
     let mut offsets = vec![];
@@ -295,25 +404,95 @@ pub fn compress(options: &CompressOptions, r: &mut dyn Read, w: &mut dyn Write)
                 // no data left.
                 break;
             }
-            Ok(n) => n as u32,
+            Ok(n) => n,
             Err(e) => return Err(HpkError::Io(e)),
         };
+        checked_u32(inflated_length)?;
 
-        let position = output_buffer.len() as u32;
-        offsets.push(position);
+        offsets.push(checked_u32(output_buffer.len() as u64)?);
 
-        match options.compressor {
-            Compression::Zlib => compress::Zlib::encode_chunk(&chunk, &mut output_buffer)?,
-            Compression::Lz4 => compress::Lz4Block::encode_chunk(&chunk, &mut output_buffer)?,
-            _ => unreachable!(),
-        };
+        options.encode_chunk(&chunk, &mut output_buffer)?;
+        progress::check(report(inflated_length))?;
     }
 
+    let inflated_length = checked_u32(inflated_length)?;
     let header_size = CompressionHeader::write(options, inflated_length, &offsets, w)?;
 
     Ok(header_size + io::copy(&mut Cursor::new(output_buffer), w)?)
 }
 
+/// Compresses a whole file, e.g. an archive or the data of an archive member
+///
+/// Only a single chunk is kept in memory. The output is written to a temp file next
+/// to it and renamed into place like [`create`], so the input can be compressed in
+/// place. Returns the size of the compressed file.
+pub fn compress_file<P>(options: &CompressOptions, input: P, output: P) -> HpkResult<u64>
+where
+    P: AsRef<Path>,
+{
+    compress_file_with_progress(options, input, output, &|_| ControlFlow::Continue(()))
+}
+
+/// Compresses a whole file like [`compress_file`] and reports the number of read bytes
+/// after every chunk
+///
+/// Returning `ControlFlow::Break(())` cancels the compression with [`HpkError::Cancelled`]
+/// and the output file is left untouched.
+pub fn compress_file_with_progress<P>(
+    options: &CompressOptions,
+    input: P,
+    output: P,
+    report: &dyn Fn(u64) -> ControlFlow<()>,
+) -> HpkResult<u64>
+where
+    P: AsRef<Path>,
+{
+    let mut fin = File::open(input)?;
+    if get_compression(&mut fin)?.is_compressed() {
+        let msg = "the input is already compressed";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into());
+    }
+    let length = fin.metadata()?.len();
+
+    let mut out = AtomicFile::new(output.as_ref())?;
+    let mut w = ChunkWriter::new(out.as_file_mut(), *options, length)?;
+    let mut read = 0;
+    loop {
+        let mut chunk = (&mut fin).take(u64::from(options.chunk_size.max(1)));
+        match io::copy(&mut chunk, &mut w)? {
+            0 => break,
+            n => read += n,
+        }
+        progress::check(report(read))?;
+    }
+    let (_, table) = w.finish_with_table()?;
+    out.commit()?;
+    Ok(table.len)
+}
+
+/// Decompresses a file written by [`compress_file`], e.g. a compressed archive or the
+/// data of a compressed archive member
+///
+/// The output is written like [`compress_file`] does. Returns the size of the
+/// decompressed file.
+pub fn decompress_file<P>(input: P, output: P) -> HpkResult<u64>
+where
+    P: AsRef<Path>,
+{
+    let mut fin = File::open(input)?;
+    if !get_compression(&mut fin)?.is_compressed() {
+        let msg = "the input is not compressed";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, msg).into());
+    }
+    let fragment = Fragment::new(0, fin.metadata()?.len());
+    let mut r = FragmentedReader::new(&fin, &[fragment]);
+
+    let mut out = AtomicFile::new(output.as_ref())?;
+    let n = copy(&mut r, out.as_file_mut())?;
+    out.commit()?;
+    Ok(n)
+}
+
 fn decompress<T: compress::Decoder>(
     length: u64,
     r: &mut dyn Read,
@@ -335,11 +514,112 @@ fn decompress<T: compress::Decoder>(
     Ok(written)
 }
 
+fn decompress_head<T: compress::Decoder>(
+    length: u64,
+    r: &mut dyn Read,
+    n: usize,
+) -> HpkResult<Vec<u8>> {
+    let hdr = CompressionHeader::read_from(length, r)?;
+    let mut head = vec![];
+    for chunk in &hdr.chunks {
+        if head.len() >= n {
+            break;
+        }
+        let mut buf = vec![0; chunk.length as usize];
+        r.read_exact(&mut buf)?;
+        let len = head.len();
+        if T::decode_chunk(&buf, &mut head).is_err() {
+            // chunk seems to be not compressed
+            head.truncate(len);
+            head.extend_from_slice(&buf);
+        }
+    }
+    head.truncate(n);
+    Ok(head)
+}
+
+/// Reader of the file data that decompresses one chunk at a time
+struct DecompressReader<R> {
+    inner: R,
+    decode: fn(&[u8], &mut Vec<u8>) -> io::Result<u64>,
+    chunks: std::vec::IntoIter<Chunk>,
+    buf: Cursor<Vec<u8>>,
+}
+
+impl<R: Read> Read for DecompressReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.buf.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let Some(chunk) = self.chunks.next() else {
+                return Ok(0);
+            };
+            let mut data = vec![0; chunk.length as usize];
+            self.inner.read_exact(&mut data)?;
+            let mut out = vec![];
+            if (self.decode)(&data, &mut out).is_err() {
+                // chunk seems to be not compressed
+                out = data;
+            }
+            self.buf = Cursor::new(out);
+        }
+    }
+}
+
+/// Returns the length of the file data and a reader that decompresses compressed
+/// files chunk by chunk
+fn data_reader<'a>(r: &'a mut FragmentedReader<&File>) -> HpkResult<(u64, Box<dyn Read + 'a>)> {
+    use crate::compress::Decoder;
+
+    let decode: fn(&[u8], &mut Vec<u8>) -> io::Result<u64> = match get_compression(r)? {
+        Compression::Lz4 => compress::Lz4Block::decode_chunk,
+        Compression::Zlib => compress::Zlib::decode_chunk,
+        Compression::Zstd => compress::Zstd::decode_chunk,
+        Compression::None => return Ok((r.len(), Box::new(r))),
+    };
+    let hdr = CompressionHeader::read_from(r.len(), r)?;
+    let reader = DecompressReader {
+        inner: r,
+        decode,
+        chunks: hdr.chunks.into_iter(),
+        buf: Cursor::new(vec![]),
+    };
+    Ok((u64::from(hdr.inflated_length), Box::new(reader)))
+}
+
+#[derive(Clone, Copy)]
 pub struct CompressOptions {
     chunk_size: u32,
     compressor: Compression,
 }
 
+impl CompressOptions {
+    pub fn use_lz4(&mut self) {
+        self.compressor = Compression::Lz4;
+    }
+
+    pub fn use_zstd(&mut self) {
+        self.compressor = Compression::Zstd;
+    }
+
+    pub fn with_chunk_size(&mut self, chunk_size: u32) {
+        self.chunk_size = chunk_size;
+    }
+
+    fn encode_chunk(&self, chunk: &[u8], out: &mut Vec<u8>) -> io::Result<u64> {
+        use crate::compress::Encoder;
+
+        match self.compressor {
+            Compression::Zlib => compress::Zlib::encode_chunk(chunk, out),
+            Compression::Lz4 => compress::Lz4Block::encode_chunk(chunk, out),
+            Compression::Zstd => compress::Zstd::encode_chunk(chunk, out),
+            Compression::None => unreachable!(),
+        }
+    }
+}
+
 impl Default for CompressOptions {
     fn default() -> Self {
         Self {
@@ -349,7 +629,8 @@ impl Default for CompressOptions {
     }
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Compression {
     Zlib,
     Lz4,
@@ -417,11 +698,14 @@ impl CompressionHeader {
         let chunk_size = r.read_u32::<LE>()?;
         let chunks = match r.read_u32::<LE>() {
             Ok(val) => {
-                let mut offsets = vec![u64::from(val)];
-                if offsets[0] != 16 {
-                    for _ in 0..((offsets[0] - 16) / 4) {
-                        offsets.push(u64::from(r.read_u32::<LE>()?));
-                    }
+                // The first offset defines the size of the offset table
+                let first = u64::from(val);
+                if first < 16 || first > length {
+                    return Err(HpkError::InvalidCompressionHeader);
+                }
+                let mut offsets = vec![first];
+                for _ in 0..((first - 16) / 4) {
+                    offsets.push(u64::from(r.read_u32::<LE>()?));
                 }
                 let mut chunks = vec![
                     Chunk {
@@ -432,11 +716,14 @@ impl CompressionHeader {
                 ];
                 let mut len = length;
                 for (i, offset) in offsets.iter().enumerate().rev() {
+                    let length = len
+                        .checked_sub(*offset)
+                        .ok_or(HpkError::InvalidCompressionHeader)?;
                     chunks[i] = Chunk {
                         offset: *offset,
-                        length: len - offset,
+                        length,
                     };
-                    len -= chunks[i].length;
+                    len -= length;
                 }
                 chunks
             }
@@ -458,19 +745,18 @@ impl CompressionHeader {
         offsets: &[u32],
         out: &mut dyn Write,
     ) -> HpkResult<u64> {
-        const HDR_SIZE: u32 = 12;
+        const HDR_SIZE: u64 = 12;
 
         options.compressor.write_identifier(out)?;
         out.write_u32::<LE>(inflated_length)?;
         out.write_u32::<LE>(options.chunk_size)?;
 
-        let offsets_size = offsets.len() as u32 * 4;
-        let offsets = offsets.iter().map(|x| HDR_SIZE + offsets_size + x);
+        let header_size = HDR_SIZE + offsets.len() as u64 * 4;
         for offset in offsets {
-            out.write_u32::<LE>(offset)?;
+            out.write_u32::<LE>(checked_u32(header_size + u64::from(*offset))?)?;
         }
 
-        Ok(u64::from(HDR_SIZE + offsets_size))
+        Ok(header_size)
     }
 }
 
@@ -479,8 +765,12 @@ impl CompressionHeader {
 pub struct ExtractOptions {
     paths: Vec<Pattern>,
     skip_filedates: bool,
+    skip_unsafe_paths: bool,
     fix_lua_files: bool,
+    name_encoding: NameEncoding,
+    write_manifest: bool,
     verbose: bool,
+    hooks: Option<Box<dyn Hooks>>,
 }
 
 impl ExtractOptions {
@@ -492,6 +782,14 @@ impl ExtractOptions {
         self.skip_filedates = true;
     }
 
+    /// Skips members with names that would be extracted outside of the destination
+    /// folder instead of aborting with [`HpkError::UnsafeDirEntryName`].
+    ///
+    /// The error of every skipped member is passed to [`Hooks::error`].
+    pub fn skip_unsafe_paths(&mut self) {
+        self.skip_unsafe_paths = true;
+    }
+
     pub fn fix_lua_files(&mut self) {
         self.fix_lua_files = true;
     }
@@ -500,10 +798,49 @@ impl ExtractOptions {
         self.verbose = verbose;
     }
 
+    /// Reports the [`Progress`] after every extracted file. Replaces the [`Hooks`].
+    pub fn set_progress<F>(&mut self, progress: F)
+    where
+        F: Fn(&Progress<'_>) + 'static,
+    {
+        self.hooks = Some(Box::new(ProgressFn(progress)));
+    }
+
+    /// Sets the callbacks for the progress and the cancellation of the extraction
+    pub fn set_hooks<H: Hooks + 'static>(&mut self, hooks: H) {
+        self.hooks = Some(Box::new(hooks));
+    }
+
+    pub fn set_name_encoding(&mut self, encoding: NameEncoding) {
+        self.name_encoding = encoding;
+    }
+
+    /// Writes a [`Manifest`] of the extracted entries to `.hpk-manifest.json` in the
+    /// destination folder for a lossless repack with [`CreateOptions::with_manifest`].
+    pub fn write_manifest(&mut self) {
+        self.write_manifest = true;
+    }
+
     pub fn set_paths(&mut self, paths: &[Pattern]) {
         self.paths = paths.to_vec();
     }
 
+    /// Applies the extraction settings of the game's archives
+    pub fn with_game(&mut self, game: Game) {
+        self.fix_lua_files = game.cripples_lua_files();
+    }
+
+    fn check_entry(&self, entry: HpkResult<DirEntry>) -> HpkResult<Option<DirEntry>> {
+        match entry {
+            Ok(entry) => Ok(Some(entry)),
+            Err(err @ HpkError::UnsafeDirEntryName(_)) if self.skip_unsafe_paths => {
+                self.hooks().error(&err);
+                Ok(None)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
     fn matches(&self, path: &Path) -> bool {
         if self.paths.is_empty() {
             return true;
@@ -515,6 +852,30 @@ impl ExtractOptions {
         }
         false
     }
+
+    /// Counts the files and their stored bytes to be extracted
+    fn totals(&self, file: &Path) -> HpkResult<(u64, u64)> {
+        let mut walk = walk(file)?;
+        walk.set_name_encoding(self.name_encoding);
+        let (mut files, mut bytes) = (0, 0);
+        while let Some(entry) = walk.next() {
+            let Ok(entry) = entry else { continue };
+            if entry.is_dir() || !self.matches(entry.path()) {
+                continue;
+            }
+            // errors are reported by the extraction itself
+            let _ = walk.read_file(&entry, |r| {
+                files += 1;
+                bytes += r.len();
+                Ok(())
+            });
+        }
+        Ok((files, bytes))
+    }
+
+    fn hooks(&self) -> &dyn Hooks {
+        self.hooks.as_deref().unwrap_or(&NoHooks)
+    }
 }
 // }}}
 
@@ -522,57 +883,175 @@ pub fn extract<P>(options: &ExtractOptions, file: P, dest: P) -> HpkResult<()>
 where
     P: AsRef<Path>,
 {
-    let file = file.as_ref();
-    let dest = dest.as_ref();
+    let result = extract_files(options, file.as_ref(), dest.as_ref());
+    progress::report_error(options.hooks(), result)
+}
+
+fn extract_files(options: &ExtractOptions, file: &Path, dest: &Path) -> HpkResult<()> {
+    let hooks = options.hooks();
     let mut walk = walk(file)?;
+    walk.set_name_encoding(options.name_encoding);
     let _filedates = Path::new("_filedates");
 
+    let (total_files, total_bytes) = match options.hooks {
+        Some(_) => options.totals(file)?,
+        None => (0, 0),
+    };
+    let mut progress = Progress {
+        total_files,
+        total_bytes,
+        ..Progress::default()
+    };
+
+    let mut manifest = if options.write_manifest {
+        let mut manifest = Manifest::new(options.name_encoding);
+        manifest.compression = archive_compression(file)?;
+        Some(manifest)
+    } else {
+        None
+    };
+
     while let Some(entry) = walk.next() {
-        if let Ok(entry) = entry {
-            let path = dest.join(entry.path());
-            if !options.matches(&entry.path) {
-                continue;
+        let entry = match options.check_entry(entry)? {
+            Some(entry) => entry,
+            None => continue,
+        };
+        let path = dest.join(entry.path());
+        if !options.matches(&entry.path) {
+            continue;
+        }
+        if entry.is_dir() {
+            if !path.exists() {
+                ::std::fs::create_dir_all(&path)?;
+            }
+            if let Some(manifest) = manifest.as_mut().filter(|_| entry.depth() > 0) {
+                manifest
+                    .entries
+                    .push(ManifestEntry::new(entry.path(), true));
             }
-            if entry.is_dir() {
-                if !path.exists() {
-                    ::std::fs::create_dir_all(&path)?;
+        } else {
+            if let Some(parent) = path.parent() {
+                if !parent.exists() {
+                    ::std::fs::create_dir_all(parent)?;
                 }
-            } else {
-                if let Some(parent) = path.parent() {
-                    if !parent.exists() {
-                        ::std::fs::create_dir_all(parent)?;
-                    }
+            }
+            progress::check(hooks.entry_start(entry.path()))?;
+            let mut stored = 0;
+            let mut inflated = 0;
+            walk.read_file(&entry, |mut r| {
+                stored = r.len();
+                let mut manifest_entry = ManifestEntry::new(entry.path(), false);
+                if manifest.is_some() {
+                    manifest_entry.compression = member_compression(&mut r)?;
                 }
-                walk.read_file(&entry, |mut r| {
-                    if options.verbose {
-                        println!("{}", path.display());
-                    }
-                    if !options.skip_filedates && entry.depth() == 1 && entry.path().eq(_filedates)
-                    {
-                        process_filedates(dest, &mut r)
-                    } else {
-                        let ext = path
-                            .extension()
-                            .and_then(OsStr::to_str)
-                            .map_or(String::new(), str::to_ascii_lowercase);
-
-                        if options.fix_lua_files && &ext[..] == "lua" {
-                            let out = File::create(path)?;
-                            copy(&mut r, &mut lua::fix_header(out))?;
-                        } else {
-                            let mut out = File::create(path)?;
-                            copy(&mut r, &mut out)?;
-                        }
-                        Ok(())
+
+                if !options.skip_filedates && entry.depth() == 1 && entry.path().eq(_filedates) {
+                    let mut buf = vec![];
+                    inflated = copy(&mut r, &mut buf)?;
+                    process_filedates(dest, options.name_encoding, &buf, |_| true)?;
+                    if let Some(manifest) = manifest.as_mut() {
+                        manifest.filedates =
+                            Some(manifest::Filedates::new(options.name_encoding, &buf));
                     }
-                })?;
+                } else {
+                    let (n, lua_fixed) = write_extracted(options, &path, entry.path(), |mut w| {
+                        copy(&mut r, &mut w)
+                    })?;
+                    inflated = n;
+                    manifest_entry.lua_fixed = lua_fixed;
+                }
+                if let Some(manifest) = manifest.as_mut() {
+                    manifest.entries.push(manifest_entry);
+                }
+                Ok(())
+            })?;
+            if options.verbose {
+                println!("{} ({} -> {} bytes)", path.display(), stored, inflated);
             }
+            let entry = ProgressEntry::new(entry.path(), inflated, stored);
+            progress::file_done(hooks, &mut progress, entry, stored)?;
         }
     }
+    if let Some(manifest) = manifest {
+        manifest.write_to(dest.join(MANIFEST_NAME))?;
+    }
     Ok(())
 }
 
-fn process_filedates<P: AsRef<Path>>(dest: P, r: &mut FragmentedReader<&File>) -> HpkResult<()> {
+/// Writes the data of an extracted file, the Lua bytecode header is fixed if enabled
+///
+/// Returns the number of written bytes and whether the Lua header was rewritten. A
+/// partially written file is removed if the data can't be written.
+fn write_extracted<F>(
+    options: &ExtractOptions,
+    path: &Path,
+    entry_path: &Path,
+    copy_data: F,
+) -> HpkResult<(u64, bool)>
+where
+    F: FnOnce(&mut dyn Write) -> HpkResult<u64>,
+{
+    let ext = path
+        .extension()
+        .and_then(OsStr::to_str)
+        .map_or(String::new(), str::to_ascii_lowercase);
+
+    let out = HookWriter::new(File::create(path)?, options.hooks(), entry_path);
+    let result = if options.fix_lua_files && &ext[..] == "lua" {
+        let mut out = lua::fix_header(out);
+        copy_data(&mut out).and_then(|n| {
+            out.flush()?;
+            Ok((n, out.is_rewritten()))
+        })
+    } else {
+        let mut out = out;
+        copy_data(&mut out).map(|n| (n, false))
+    };
+    if result.is_err() {
+        // don't leave a partially written file behind
+        let _ = std::fs::remove_file(path);
+    }
+    result
+}
+
+/// Returns the encoder and chunk size of a compressed archive
+fn archive_compression(file: &Path) -> HpkResult<Option<ChunkedCompression>> {
+    let mut f = File::open(file)?;
+    let len = f.metadata()?.len();
+    chunked_compression(len, &mut f)
+}
+
+/// Returns the encoder and chunk size of a compressed member
+fn member_compression(r: &mut FragmentedReader<&File>) -> HpkResult<Option<ChunkedCompression>> {
+    let compression = chunked_compression(r.len(), r)?;
+    r.rewind()?;
+    Ok(compression)
+}
+
+fn chunked_compression<T: Read + Seek>(
+    len: u64,
+    r: &mut T,
+) -> HpkResult<Option<ChunkedCompression>> {
+    let codec = get_compression(r)?;
+    if !codec.is_compressed() {
+        return Ok(None);
+    }
+    let hdr = CompressionHeader::read_from(len, r)?;
+    Ok(Some(ChunkedCompression {
+        codec,
+        chunk_size: hdr.chunk_size,
+    }))
+}
+
+/// Applies the file times of a `_filedates` file to the extracted files
+///
+/// Only the paths accepted by the filter are touched, the path is relative to the
+/// destination folder.
+fn process_filedates<P, F>(dest: P, encoding: NameEncoding, buf: &[u8], filter: F) -> HpkResult<()>
+where
+    P: AsRef<Path>,
+    F: Fn(&Path) -> bool,
+{
     // macro: is_valid {{{
     macro_rules! is_valid {
         ($e:expr) => {{
@@ -585,14 +1064,46 @@ fn process_filedates<P: AsRef<Path>>(dest: P, r: &mut FragmentedReader<&File>) -
     }
     // }}}
 
-    let mut buf = Cursor::new(Vec::new());
-    copy(r, &mut buf)?;
-    buf.set_position(0);
+    for (path, unix_secs) in parse_filedates(encoding, buf) {
+        if !is_safe_path(Path::new(&path)) {
+            continue;
+        }
+        let ft = filetime::FileTime::from_unix_time(unix_secs, 0);
+
+        let full_path = dest.as_ref().join(&path);
+        if is_valid!(full_path) {
+            if filter(Path::new(&path)) {
+                filetime::set_file_times(full_path, ft, ft)?;
+            }
+        } else {
+            // Remove the first component of the path and try again because
+            // Grand Ages: Rome adds the basename of the original hpk file to the path
+            let mut comps = Path::new(&path).components();
+            comps.next();
+
+            let path = dest.as_ref().join(comps.as_path());
+            if is_valid!(path) && filter(comps.as_path()) {
+                filetime::set_file_times(path, ft, ft)?;
+            }
+        }
+    }
+    Ok(())
+}
 
-    let br = io::BufReader::new(buf);
-    for line in br.lines() {
-        let line = line?;
+/// Parses the entries of a `_filedates` file as paths and Unix timestamps
+///
+/// Lines with paths that are not valid in the encoding are skipped.
+fn parse_filedates(encoding: NameEncoding, buf: &[u8]) -> Vec<(String, i64)> {
+    let mut filedates = vec![];
+    for line in buf.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let Some(line) = encoding.decode(line) else {
+            continue;
+        };
         let entry: Vec<_> = line.rsplitn(2, '=').collect();
+        if entry.len() != 2 {
+            continue;
+        }
         if let Ok(val) = entry[0].parse::<i64>() {
             // This catches the different file time formats.
             // Multiplication overflows for the Windows file time
@@ -601,25 +1112,154 @@ fn process_filedates<P: AsRef<Path>>(dest: P, r: &mut FragmentedReader<&File>) -
                 None => val,
             };
             let unix_secs = (val / WINDOWS_TICKS) - SEC_TO_UNIX_EPOCH;
-            let ft = filetime::FileTime::from_unix_time(unix_secs, 0);
+            filedates.push((entry[1].to_string(), unix_secs));
+        }
+    }
+    filedates
+}
 
-            let path = dest.as_ref().join(entry[1]);
-            if is_valid!(path) {
-                filetime::set_file_times(path, ft, ft)?;
-            } else {
-                // Remove the first component of the path and try again because
-                // Grand Ages: Rome adds the basename of the original hpk file to the path
-                let mut comps = Path::new(entry[1]).components();
+/// Writes the members of the archive as tar stream
+///
+/// The timestamps of a `_filedates` file are applied as modification times
+/// and the `_filedates` file itself is only included if it's not processed.
+pub fn extract_to_tar<P, W>(options: &ExtractOptions, file: P, w: W) -> HpkResult<W>
+where
+    P: AsRef<Path>,
+    W: Write,
+{
+    let result = write_tar(options, file.as_ref(), w);
+    progress::report_error(options.hooks(), result)
+}
+
+fn write_tar<W: Write>(options: &ExtractOptions, file: &Path, w: W) -> HpkResult<W> {
+    let hooks = options.hooks();
+    let mut walk = walk(file)?;
+    walk.set_name_encoding(options.name_encoding);
+    let filedates_path = Path::new("_filedates");
+
+    let mut entries = vec![];
+    for entry in walk.by_ref() {
+        match options.check_entry(entry)? {
+            Some(entry) if entry.depth() > 0 => entries.push(entry),
+            _ => {}
+        }
+    }
+
+    let mut mtimes = HashMap::new();
+    if !options.skip_filedates {
+        let filedates = entries
+            .iter()
+            .find(|e| !e.is_dir() && e.depth() == 1 && e.path() == filedates_path);
+        if let Some(entry) = filedates {
+            let mut filedates = vec![];
+            walk.read_file(entry, |mut r| {
+                let mut buf = vec![];
+                copy(&mut r, &mut buf)?;
+                filedates = parse_filedates(options.name_encoding, &buf);
+                Ok(())
+            })?;
+            // Grand Ages: Rome adds the basename of the original hpk file to the path,
+            // the unprefixed paths are added first to prefer exact matches.
+            for (path, unix_secs) in &filedates {
+                let mut comps = Path::new(path).components();
                 comps.next();
+                mtimes.insert(comps.as_path().to_path_buf(), *unix_secs);
+            }
+            for (path, unix_secs) in filedates {
+                mtimes.insert(PathBuf::from(path), unix_secs);
+            }
+        }
+    }
+
+    let is_filedates =
+        |e: &DirEntry| !options.skip_filedates && e.depth() == 1 && e.path() == filedates_path;
+    let mut progress = Progress::default();
+    if options.hooks.is_some() {
+        for entry in &entries {
+            if entry.is_dir() || is_filedates(entry) || !options.matches(entry.path()) {
+                continue;
+            }
+            walk.read_file(entry, |r| {
+                progress.total_files += 1;
+                progress.total_bytes += r.len();
+                Ok(())
+            })?;
+        }
+    }
+
+    let mut builder = tar::Builder::new(w);
+    for entry in &entries {
+        if !options.matches(entry.path()) {
+            continue;
+        }
+        let mut header = tar::Header::new_gnu();
+        let mtime = mtimes.get(entry.path()).copied().unwrap_or_default();
+        header.set_mtime(u64::try_from(mtime).unwrap_or_default());
+
+        if entry.is_dir() {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            builder.append_data(&mut header, entry.path(), io::empty())?;
+            continue;
+        }
+        if is_filedates(entry) {
+            continue;
+        }
+
+        let ext = entry
+            .path()
+            .extension()
+            .and_then(OsStr::to_str)
+            .map_or(String::new(), str::to_ascii_lowercase);
 
-                let path = dest.as_ref().join(comps.as_path());
-                if is_valid!(path) {
-                    filetime::set_file_times(path, ft, ft)?;
+        progress::check(hooks.entry_start(entry.path()))?;
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_mode(0o644);
+        let (mut stored, mut size) = (0, 0);
+        walk.read_file(entry, |mut r| {
+            stored = r.len();
+            // only a Lua file whose header is fixed changes its size and is buffered
+            if options.fix_lua_files && &ext[..] == "lua" {
+                let head = read_head(&mut r, lua::MAX_HEADER_LEN)?;
+                r.rewind()?;
+                if let LuaScript::Bytecode(hdr) = lua::inspect(&head) {
+                    if hdr.crippled {
+                        let mut data = vec![];
+                        let out = HookWriter::new(&mut data, hooks, entry.path());
+                        let mut out = lua::fix_header(out);
+                        copy(&mut r, &mut out)?;
+                        out.flush()?;
+                        drop(out);
+
+                        size = data.len() as u64;
+                        header.set_size(size);
+                        builder.append_data(&mut header, entry.path(), &data[..])?;
+                        return Ok(());
+                    }
                 }
             }
+
+            let (length, data) = data_reader(&mut r)?;
+            size = length;
+            header.set_size(size);
+            let mut data = HookReader::new(data.take(size), hooks, entry.path());
+            builder.append_data(&mut header, entry.path(), &mut data)?;
+            if data.bytes_read() != size {
+                let msg = "length of the file data differs from its header";
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg).into());
+            }
+            Ok(())
+        })?;
+
+        if options.verbose {
+            let path = entry.path().display();
+            eprintln!("{} ({} -> {} bytes)", path, stored, size);
         }
+        let entry = ProgressEntry::new(entry.path(), size, stored);
+        progress::file_done(hooks, &mut progress, entry, stored)?;
     }
-    Ok(())
+    Ok(builder.into_inner()?)
 }
 
 pub fn copy<W>(r: &mut FragmentedReader<&File>, w: &mut W) -> HpkResult<u64>
@@ -630,12 +1270,30 @@ where
         Compression::Lz4 => decompress::<compress::Lz4Block>(r.len(), r, w),
         Compression::Zlib => decompress::<compress::Zlib>(r.len(), r, w),
         Compression::Zstd => decompress::<compress::Zstd>(r.len(), r, w),
-        Compression::None => io::copy(r, w).map_err(HpkError::Io),
+        Compression::None => Ok(io::copy(r, w)?),
+    }
+}
+
+/// Reads the first `n` bytes of the file data, compressed files are decompressed
+///
+/// Only the chunks needed for the `n` bytes are read and decompressed.
+pub fn read_head(r: &mut FragmentedReader<&File>, n: usize) -> HpkResult<Vec<u8>> {
+    match get_compression(r)? {
+        Compression::Lz4 => decompress_head::<compress::Lz4Block>(r.len(), r, n),
+        Compression::Zlib => decompress_head::<compress::Zlib>(r.len(), r, n),
+        Compression::Zstd => decompress_head::<compress::Zstd>(r.len(), r, n),
+        Compression::None => {
+            let mut buf = vec![];
+            r.by_ref().take(n as u64).read_to_end(&mut buf)?;
+            Ok(buf)
+        }
     }
 }
 
 // struct CreateOptions {{{
-enum FileDateFormat {
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileDateFormat {
     Default,
     Short,
 }
@@ -646,6 +1304,15 @@ pub struct CreateOptions {
     cripple_lua_files: bool,
     extensions: Vec<String>,
     filedates_fmt: Option<FileDateFormat>,
+    name_encoding: NameEncoding,
+    manifest: Option<Manifest>,
+    min_savings: Option<u8>,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    min_size: u64,
+    max_size: Option<u64>,
+    verbose: bool,
+    hooks: Option<Box<dyn Hooks>>,
 }
 
 impl Default for CreateOptions {
@@ -665,6 +1332,15 @@ impl Default for CreateOptions {
                 "csv".into(),
             ],
             filedates_fmt: None,
+            name_encoding: NameEncoding::default(),
+            manifest: None,
+            min_savings: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            min_size: 0,
+            max_size: None,
+            verbose: false,
+            hooks: None,
         }
     }
 }
@@ -682,6 +1358,10 @@ impl CreateOptions {
         self.compress_options.compressor = Compression::Lz4;
     }
 
+    pub fn use_zstd(&mut self) {
+        self.compress_options.compressor = Compression::Zstd;
+    }
+
     pub fn cripple_lua_files(&mut self) {
         self.cripple_lua_files = true;
     }
@@ -690,10 +1370,63 @@ impl CreateOptions {
         self.compress_options.chunk_size = chunk_size;
     }
 
+    pub fn set_name_encoding(&mut self, encoding: NameEncoding) {
+        self.name_encoding = encoding;
+    }
+
     pub fn with_extensions(&mut self, ext: Vec<String>) {
         self.extensions = ext;
     }
 
+    /// Tries to compress every file regardless of its extension and stores it
+    /// uncompressed if the compression saves less than `percent` of the file size,
+    /// e.g. for already compressed DDS textures or OGG sounds.
+    pub fn with_min_savings(&mut self, percent: u8) {
+        self.min_savings = Some(percent.min(100));
+    }
+
+    /// Compresses the files matching one of the patterns regardless of their extension
+    pub fn set_include(&mut self, patterns: &[Pattern]) {
+        self.include = patterns.to_vec();
+    }
+
+    /// Never compresses the files matching one of the patterns
+    pub fn set_exclude(&mut self, patterns: &[Pattern]) {
+        self.exclude = patterns.to_vec();
+    }
+
+    /// Stores files smaller than `size` bytes uncompressed
+    pub fn with_min_compress_size(&mut self, size: u64) {
+        self.min_size = size;
+    }
+
+    /// Stores files larger than `size` bytes uncompressed
+    pub fn with_max_compress_size(&mut self, size: u64) {
+        self.max_size = Some(size);
+    }
+
+    /// Prints the compression decision for every file
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    /// Reports the [`Progress`] after every added file. Replaces the [`Hooks`].
+    pub fn set_progress<F>(&mut self, progress: F)
+    where
+        F: Fn(&Progress<'_>) + 'static,
+    {
+        self.hooks = Some(Box::new(ProgressFn(progress)));
+    }
+
+    /// Sets the callbacks for the progress and the cancellation of the creation
+    pub fn set_hooks<H: Hooks + 'static>(&mut self, hooks: H) {
+        self.hooks = Some(Box::new(hooks));
+    }
+
+    fn hooks(&self) -> &dyn Hooks {
+        self.hooks.as_deref().unwrap_or(&NoHooks)
+    }
+
     pub fn with_default_filedates_format(&mut self) {
         self.filedates_fmt = Some(FileDateFormat::Default);
     }
@@ -702,10 +1435,108 @@ impl CreateOptions {
         self.filedates_fmt = Some(FileDateFormat::Short);
     }
 
+    /// Applies the encoder, compressed file extensions, filedates format and
+    /// Lua bytecode handling used by the game's archives
+    pub fn with_game(&mut self, game: Game) {
+        let defaults = CreateOptions::default();
+
+        self.compress_options.compressor = if game.uses_lz4() {
+            Compression::Lz4
+        } else {
+            Compression::Zlib
+        };
+        self.extensions = if game.compresses_files() {
+            defaults.extensions
+        } else {
+            Vec::new()
+        };
+        self.filedates_fmt = game.filedates_format();
+        self.cripple_lua_files = game.cripples_lua_files();
+    }
+
+    /// Applies the settings detected in an existing archive
+    ///
+    /// Only the file extensions of the compressed files in the archive are compressed,
+    /// the default extensions are replaced. No files are compressed if the archive
+    /// doesn't contain compressed files. An archive compressed as a whole passes its
+    /// encoder on to the compressed files.
+    pub fn like(&mut self, info: &ArchiveInfo) {
+        self.compress = info.compression.is_compressed();
+        self.compress_options.compressor = match info.compressor() {
+            _ if self.compress => info.compression,
+            Compression::None => Compression::Zlib,
+            c => c,
+        };
+        if let Some(chunk_size) = info.chunk_size {
+            self.compress_options.chunk_size = chunk_size;
+        }
+        self.extensions = info.compressed_extensions.clone();
+        self.filedates_fmt = info.filedates;
+        self.cripple_lua_files = info.lua.crippled > 0;
+    }
+
+    /// Rebuilds the archive of a [`Manifest`] written by [`ExtractOptions::write_manifest`]
+    ///
+    /// The entries keep the order and the compression of the original archive and the
+    /// `_filedates` file keeps its original lines. Files that are not listed in the
+    /// manifest are added after the listed entries of their directory with the regular
+    /// settings and listed entries missing in the input folder are skipped.
+    pub fn with_manifest(&mut self, manifest: Manifest) {
+        if let Some(c) = manifest.compression {
+            self.compress = true;
+            self.compress_options = CompressOptions {
+                chunk_size: c.chunk_size,
+                compressor: c.codec,
+            };
+        }
+        if let Some(filedates) = &manifest.filedates {
+            self.filedates_fmt = Some(filedates.format);
+        }
+        self.name_encoding = manifest.name_encoding;
+        self.manifest = Some(manifest);
+    }
+
     fn with_filedates(&self) -> bool {
         self.filedates_fmt.is_some()
     }
 
+    /// Returns the compression and Lua header handling of a file
+    ///
+    /// Excluded files and files outside of the size limits are stored uncompressed.
+    /// The remaining files are compressed if they match an include pattern or the
+    /// extension list or if the smart mode is enabled.
+    fn file_settings(&self, path: &Path, entry: Option<&ManifestEntry>, size: u64) -> FileSettings {
+        if let Some(entry) = entry {
+            return FileSettings::from_manifest(entry);
+        }
+        let ext = path
+            .extension()
+            .and_then(OsStr::to_str)
+            .map_or(String::new(), str::to_ascii_lowercase);
+        let matches = |patterns: &[Pattern]| patterns.iter().any(|p| p.matches_path(path));
+
+        let stored = if matches(&self.exclude) {
+            Some(Stored::Excluded)
+        } else if !(matches(&self.include)
+            || self.min_savings.is_some()
+            || self.extensions.contains(&ext))
+        {
+            Some(Stored::Extension)
+        } else if size < self.min_size {
+            Some(Stored::MinSize)
+        } else if self.max_size.is_some_and(|max| size > max) {
+            Some(Stored::MaxSize)
+        } else {
+            None
+        };
+        FileSettings {
+            compress: stored.is_none().then_some(self.compress_options),
+            min_savings: self.min_savings,
+            cripple_lua: self.cripple_lua_files && &ext[..] == "lua",
+            stored,
+        }
+    }
+
     /// Calculates the file time for the _filedates file
     ///
     /// The actually values for Tropico 3 and Grand Ages: Rome are stored
@@ -730,153 +1561,469 @@ impl CreateOptions {
 }
 // }}}
 
-pub fn create<P>(options: &CreateOptions, dir: P, file: P) -> HpkResult<()>
-where
-    P: AsRef<Path>,
-{
-    use std::collections::HashMap;
-    use walkdir::WalkDir;
+struct FileSettings {
+    compress: Option<CompressOptions>,
+    /// Minimum savings in percent to keep the compressed data
+    min_savings: Option<u8>,
+    cripple_lua: bool,
+    /// Reason for storing the file uncompressed
+    stored: Option<Stored>,
+}
 
-    // macro: strip_prefix {{{
-    macro_rules! strip_prefix {
-        (dir $path: expr) => {{
-            let path = $path.strip_prefix(&dir).unwrap();
-            let parent = path.parent();
-            (path, parent)
-        }};
-        (file $path: expr) => {{
-            let (path, parent) = strip_prefix!(dir $path);
-            (path, parent.unwrap())
-        }};
+impl FileSettings {
+    fn from_manifest(entry: &ManifestEntry) -> Self {
+        let compress = entry
+            .compression
+            .filter(|c| c.codec.is_compressed())
+            .map(|c| CompressOptions {
+                chunk_size: c.chunk_size,
+                compressor: c.codec,
+            });
+        FileSettings {
+            stored: compress.is_none().then_some(Stored::Manifest),
+            compress,
+            min_savings: None,
+            cripple_lua: entry.lua_fixed,
+        }
     }
-    // }}}
+}
 
-    let (mut w, tmpfile, _tmpdir) = {
-        if options.compress {
-            let tempdir = tempfile::Builder::new().prefix("hpk").tempdir()?;
-            let tmpfile = tempdir.path().join(
-                file.as_ref()
-                    .file_name()
-                    .and_then(OsStr::to_str)
-                    .unwrap_or("temp.hpk"),
-            );
-            (File::create(&tmpfile)?, Some(tmpfile), Some(tempdir))
-        } else {
-            (File::create(&file)?, None, None)
-        }
-    };
+#[derive(Clone, Copy)]
+enum Stored {
+    Manifest,
+    Excluded,
+    Extension,
+    MinSize,
+    MaxSize,
+    Savings(u8),
+}
 
-    w.seek(SeekFrom::Start(u64::from(HEADER_LENGTH)))?;
+impl fmt::Display for Stored {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stored::Manifest => f.write_str("listed in manifest"),
+            Stored::Excluded => f.write_str("excluded"),
+            Stored::Extension => f.write_str("extension not compressed"),
+            Stored::MinSize => f.write_str("below minimum size"),
+            Stored::MaxSize => f.write_str("above maximum size"),
+            Stored::Savings(percent) => write!(f, "savings below {}%", percent),
+        }
+    }
+}
 
-    let mut filedates = vec![];
-    let mut fragments: Vec<Fragment> = vec![];
-    let mut stack = HashMap::new();
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum CreateKind {
+    File,
+    Dir,
+    /// The generated `_filedates` file
+    Filedates,
+}
 
-    let walkdir = WalkDir::new(&dir).contents_first(true).sort_by_file_name();
+/// Creates the archive from the files of the input folder
+///
+/// The archive is written to a temp file next to the output file, which replaces the
+/// output file once the archive is complete. An existing output file is left untouched
+/// if the creation fails or is cancelled by the [`Hooks`].
+///
+/// A compressed archive is written without a temporary copy. This is a trade-off:
+/// the archive is measured in a dry run first, which compresses the files once more
+/// if their compressed data doesn't fit into the memory kept for the second run.
+pub fn create<P>(options: &CreateOptions, dir: P, file: P) -> HpkResult<()>
+where
+    P: AsRef<Path>,
+{
+    let file = file.as_ref();
+    let mut out = AtomicFile::new(file)?;
+    let result = create_archive(options, dir.as_ref(), out.as_file_mut());
+    let result = result.and_then(|()| out.commit());
+    progress::report_error(options.hooks(), result)
+}
 
-    for entry in walkdir {
-        let entry = entry?;
+fn create_archive(options: &CreateOptions, dir: &Path, out: &mut File) -> HpkResult<()> {
+    let entries = creation_order(options, dir)?;
 
-        // write filedate entry
-        if options.with_filedates() && entry.depth() > 0 {
-            let val = options.filedates_value_for_path(entry.path())?;
-            let (path, _) = strip_prefix!(dir entry.path());
-            writeln!(filedates, "{}={}", path.display(), val)?;
-        }
-
-        if entry.file_type().is_file() {
-            let (path, parent) = strip_prefix!(file entry.path());
-
-            fragments.push(write_file(options, entry.path(), &mut w)?);
-            let index = fragments.len() + 1;
-            let parent_buf = stack.entry(parent.to_path_buf()).or_default();
-            let dent = DirEntry::new_file(path, index, entry.depth());
-            dent.write(parent_buf)?;
-        } else if entry.file_type().is_dir() {
-            let (path, parent) = strip_prefix!(dir entry.path());
-            let mut dir_buffer = stack.remove(&path.to_path_buf()).unwrap_or_default();
-
-            // write _filedates in the root dir buffer
-            if options.with_filedates() && entry.depth() == 0 {
-                let mut buf = Cursor::new(&filedates);
-                let position = w.stream_position()?;
-                let n = io::copy(&mut buf, &mut w)?;
-
-                fragments.push(Fragment::new(position, n));
-                let index = fragments.len() + 1;
-                let dent = DirEntry::new_file("_filedates", index, 1);
-                dent.write(&mut dir_buffer)?;
-            }
-
-            let position = w.stream_position()?;
-            let mut r = Cursor::new(dir_buffer);
-            let n = io::copy(&mut r, &mut w)?;
-
-            let fragment = Fragment::new(position, n);
-            if entry.depth() > 0 {
-                fragments.push(fragment);
-                let index = fragments.len() + 1;
-                let dent = DirEntry::new_dir(path, index, entry.depth());
-                let parent_buf = stack
-                    .entry(parent.expect("bug?").to_path_buf())
-                    .or_insert_with(Vec::new);
-                dent.write(parent_buf)?;
-            } else {
-                // root dir must be the first fragment
-                fragments.insert(0, fragment);
+    let mut filedates = vec![];
+    if options.with_filedates() {
+        let mut known = std::collections::HashSet::new();
+        if let Some(fd) = options.manifest.as_ref().and_then(|m| m.filedates.as_ref()) {
+            filedates = fd.to_bytes(options.name_encoding);
+            known.extend(
+                fd.lines
+                    .iter()
+                    .filter_map(|l| l.rsplit_once('='))
+                    .map(|l| l.0),
+            );
+        }
+        for (path, kind, _) in &entries {
+            let display = path.display().to_string();
+            if path.as_os_str().is_empty() || *kind == CreateKind::Filedates {
+                continue;
+            }
+            if known.contains(&display[..]) {
+                continue;
             }
+            let val = options.filedates_value_for_path(dir.join(path))?;
+            let line = format!("{}={}\n", display, val);
+            let line = options
+                .name_encoding
+                .encode(OsStr::new(&line))
+                .ok_or(HpkError::InvalidDirEntryName)?;
+            filedates.extend_from_slice(&line);
         }
     }
 
-    let fragmented_filesystem_offset = w.stream_position()?;
-    let fragmented_filesystem_length = fragments.len() as u64 * 8;
-    for fragment in fragments {
-        fragment.write(&mut w)?;
+    let mut progress = Progress::default();
+    if options.hooks.is_some() {
+        for (path, kind, _) in &entries {
+            progress.total_bytes += match kind {
+                CreateKind::Dir => continue,
+                CreateKind::File => dir.join(path).metadata()?.len(),
+                CreateKind::Filedates => filedates.len() as u64,
+            };
+            progress.total_files += 1;
+        }
     }
 
-    w.rewind()?;
-    let header = Header::new(fragmented_filesystem_offset, fragmented_filesystem_length);
-    header.write(&mut w)?;
-
-    // Compress the temp file
-    if let Some(tmpfile) = tmpfile {
-        w.sync_data()?;
-        let mut input = File::open(tmpfile)?;
-        let mut out = File::create(file)?;
-        compress(&options.compress_options, &mut input, &mut out)?;
+    if options.compress {
+        // A dry run measures the uncompressed archive to reserve the offset table
+        // of the chunks, then the archive is compressed while it's written. The
+        // compressed files are kept in memory up to `KEPT_DATA_LIMIT` for the second
+        // run, the files above the limit are read and compressed twice.
+        let mut measure = Progress {
+            stage: ProgressStage::Measure,
+            ..progress
+        };
+        let mut tables = ChunkTables::record();
+        let writer = HpkWriter::new(SizeCounter::default())?;
+        let (_, header) = write_entries(
+            options,
+            dir,
+            &entries,
+            &filedates,
+            &mut tables,
+            writer,
+            &mut measure,
+        )?;
+
+        let mut tables = tables.replay();
+        let length = header.fragmented_filesystem_offset + header.fragmented_filesystem_length;
+        let w = ChunkWriter::new(out, options.compress_options, length)?;
+        let writer = HpkWriter::with_header(w, header)?;
+        let (w, _) = write_entries(
+            options,
+            dir,
+            &entries,
+            &filedates,
+            &mut tables,
+            writer,
+            &mut progress,
+        )?;
+        w.finish()?;
+    } else {
+        let writer = HpkWriter::new(out)?;
+        let mut tables = ChunkTables::Rewrite;
+        write_entries(
+            options,
+            dir,
+            &entries,
+            &filedates,
+            &mut tables,
+            writer,
+            &mut progress,
+        )?;
     }
+    Ok(())
+}
 
-    return Ok(());
+/// Writes the entries in the creation order and returns the written header
+///
+/// The compression decisions are only printed in the [`ProgressStage::Entries`] stage.
+fn write_entries<W: Write + Seek>(
+    options: &CreateOptions,
+    dir: &Path,
+    entries: &[(PathBuf, CreateKind, Option<&ManifestEntry>)],
+    filedates: &[u8],
+    tables: &mut ChunkTables,
+    mut writer: HpkWriter<W>,
+    progress: &mut Progress<'_>,
+) -> HpkResult<(W, Header)> {
+    let hooks = options.hooks();
+    let verbose = options.verbose && progress.stage == ProgressStage::Entries;
+    writer.set_name_encoding(options.name_encoding);
+
+    for (path, kind, entry) in entries {
+        match kind {
+            CreateKind::Dir => writer.add_dir(path)?,
+            CreateKind::File => {
+                progress::check(hooks.entry_start(path))?;
+                let mut fin = File::open(dir.join(path))?;
+                let size = fin.metadata()?.len();
+                let mut settings = options.file_settings(path, *entry, size);
+                let n = writer.add_file_with(path, |w| {
+                    let mut w = HookWriter::new(w, hooks, path);
+                    write_file(&mut settings, tables, &mut fin, &mut w)
+                })?;
+                if verbose {
+                    print_decision(path, &settings, size, n);
+                }
+                let entry = ProgressEntry::new(path, size, n);
+                progress::file_done(hooks, progress, entry, size)?;
+            }
+            CreateKind::Filedates => {
+                let mut settings = match entry {
+                    Some(entry) => FileSettings::from_manifest(entry),
+                    None => FileSettings {
+                        compress: None,
+                        min_savings: None,
+                        cripple_lua: false,
+                        stored: Some(Stored::Extension),
+                    },
+                };
+                progress::check(hooks.entry_start(path))?;
+                let size = filedates.len() as u64;
+                let mut r = Cursor::new(filedates);
+                let n = writer.add_file_with(path, |w| {
+                    let mut w = HookWriter::new(w, hooks, path);
+                    write_file(&mut settings, tables, &mut r, &mut w)
+                })?;
+                if verbose {
+                    print_decision(path, &settings, size, n);
+                }
+                let entry = ProgressEntry::new(path, size, n);
+                progress::file_done(hooks, progress, entry, size)?;
+            }
+        }
+    }
+    return writer.finish_with_header();
 
     // write_file {{{
-    fn write_file<W>(options: &CreateOptions, file: &Path, w: &mut W) -> HpkResult<Fragment>
+    /// Writes the file and falls back to storing it uncompressed in smart mode
+    /// if the compression doesn't save enough space
+    ///
+    /// Only a single chunk of a compressed file is kept in memory, except for the files
+    /// kept by the dry run. The smart mode compresses the file twice, first to measure
+    /// the compressed size, unless the dry run already measured it.
+    fn write_file<R, W>(
+        settings: &mut FileSettings,
+        tables: &mut ChunkTables,
+        r: &mut R,
+        w: &mut W,
+    ) -> HpkResult<u64>
     where
+        R: Read + Seek,
         W: Write + Seek,
     {
-        let ext = file
-            .extension()
-            .and_then(OsStr::to_str)
-            .map_or(String::new(), str::to_ascii_lowercase);
-        let _compress = options.extensions.contains(&ext);
-
-        let mut fin = File::open(file)?;
-        let position = w.stream_position()?;
-        let n = if options.cripple_lua_files && &ext[..] == "lua" {
-            let mut r = lua::cripple_header(&mut fin);
-            if _compress {
-                compress(&options.compress_options, &mut r, w)?
-            } else {
-                io::copy(&mut r, w)?
-            }
-        } else if _compress {
-            compress(&options.compress_options, &mut fin, w)?
+        let options = match settings.compress {
+            Some(options) => options,
+            None => return write_data(settings, r, w),
+        };
+        let size = r.seek(SeekFrom::End(0))?;
+        r.rewind()?;
+        let length = if settings.cripple_lua {
+            let n = write_data(settings, r, &mut io::sink())?;
+            r.rewind()?;
+            n
         } else {
-            io::copy(&mut fin, w)?
+            size
+        };
+
+        let saves = |table: &ChunkTable| match settings.min_savings {
+            Some(min_savings) => table.len * 100 <= size * u64::from(100 - min_savings),
+            None => true,
+        };
+        let compressed = match tables {
+            ChunkTables::Replay(files) => Some(files.pop_front().ok_or_else(changed_input)?),
+            ChunkTables::Record { kept, .. } => {
+                // the data is kept for the real run while the memory isn't used up
+                let (table, data) = if *kept + length <= KEPT_DATA_LIMIT {
+                    let mut cw = ChunkWriter::new(Cursor::new(vec![]), options, length)?;
+                    write_data(settings, r, &mut cw)?;
+                    let (buf, table) = cw.finish_with_table()?;
+                    (table, Some(buf.into_inner()))
+                } else {
+                    let mut cw = ChunkWriter::new(SizeCounter::default(), options, length)?;
+                    write_data(settings, r, &mut cw)?;
+                    (cw.finish_with_table()?.1, None)
+                };
+                r.rewind()?;
+                if !saves(&table) {
+                    Some(None)
+                } else {
+                    *kept += data.as_ref().map_or(0, |d| d.len() as u64);
+                    Some(Some(Compressed { table, data }))
+                }
+            }
+            ChunkTables::Rewrite if settings.min_savings.is_some() => {
+                let mut cw = ChunkWriter::new(SizeCounter::default(), options, length)?;
+                write_data(settings, r, &mut cw)?;
+                r.rewind()?;
+                let (_, table) = cw.finish_with_table()?;
+                let data = None;
+                Some(Some(Compressed { table, data }).filter(|c| saves(&c.table)))
+            }
+            ChunkTables::Rewrite => None,
+        };
+
+        let (n, compressed) = match compressed {
+            Some(None) => {
+                settings.compress = None;
+                settings.stored = settings.min_savings.map(Stored::Savings);
+                (write_data(settings, r, w)?, None)
+            }
+            Some(Some(c)) if matches!(tables, ChunkTables::Record { .. }) => {
+                // the dry run only needs the size of the measured data
+                io::copy(&mut io::repeat(0).take(c.table.len), w)?;
+                (c.table.len, Some(c))
+            }
+            Some(Some(Compressed {
+                table,
+                data: Some(data),
+            })) => {
+                w.write_all(&data)?;
+                (table.len, None)
+            }
+            Some(Some(Compressed { table, data: None })) => {
+                let mut cw = ChunkWriter::with_table(w, options, length, table)?;
+                write_data(settings, r, &mut cw)?;
+                let (_, table) = cw.finish_with_table()?;
+                (table.len, None)
+            }
+            None => {
+                let mut cw = ChunkWriter::new(w, options, length)?;
+                write_data(settings, r, &mut cw)?;
+                let (_, table) = cw.finish_with_table()?;
+                (table.len, None)
+            }
         };
+        if let ChunkTables::Record { files, .. } = tables {
+            files.push_back(compressed);
+        }
+        Ok(n)
+    }
+
+    /// Copies the data and cripples the header of Lua files
+    fn write_data(settings: &FileSettings, r: &mut dyn Read, w: &mut dyn Write) -> HpkResult<u64> {
+        if settings.cripple_lua {
+            let mut r = lua::cripple_header(r);
+            Ok(io::copy(&mut r, w)?)
+        } else {
+            Ok(io::copy(r, w)?)
+        }
+    }
 
-        Ok(Fragment::new(position, n))
+    fn print_decision(path: &Path, settings: &FileSettings, size: u64, n: u64) {
+        match (&settings.compress, settings.stored) {
+            (Some(options), _) => {
+                let ratio = if size == 0 {
+                    0.0
+                } else {
+                    n as f64 * 100.0 / size as f64
+                };
+                println!(
+                    "{}: {} {} -> {} bytes ({:.1}%)",
+                    path.display(),
+                    options.compressor,
+                    size,
+                    n,
+                    ratio
+                );
+            }
+            (None, Some(reason)) => {
+                println!("{}: stored {} bytes ({})", path.display(), size, reason);
+            }
+            (None, None) => println!("{}: stored {} bytes", path.display(), size),
+        }
     }
     // }}}
 }
 
+/// Returns the entries of the input folder in the order they are written
+///
+/// The entries of a directory are written before the directory listing. Without a
+/// manifest the entries are sorted by name and `_filedates` is the last root entry.
+fn creation_order<'a>(
+    options: &'a CreateOptions,
+    dir: &Path,
+) -> HpkResult<Vec<(PathBuf, CreateKind, Option<&'a ManifestEntry>)>> {
+    use std::collections::{HashMap, HashSet};
+    use walkdir::WalkDir;
+
+    type Listing<'a> = Vec<(PathBuf, CreateKind, Option<&'a ManifestEntry>)>;
+
+    let mut on_disk = vec![];
+    for entry in WalkDir::new(dir).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let path = entry.path().strip_prefix(dir).unwrap().to_path_buf();
+        if entry.depth() == 1 && path == Path::new(MANIFEST_NAME) {
+            continue;
+        }
+        if entry.file_type().is_dir() {
+            on_disk.push((path, CreateKind::Dir));
+        } else if entry.file_type().is_file() {
+            on_disk.push((path, CreateKind::File));
+        }
+    }
+
+    let _filedates = Path::new("_filedates");
+    let mut listings: HashMap<PathBuf, Listing<'a>> = HashMap::new();
+    let mut listed = HashSet::new();
+    let mut filedates_listed = !options.with_filedates();
+
+    if let Some(manifest) = &options.manifest {
+        let kinds: HashMap<_, _> = on_disk.iter().map(|(p, k)| (p.as_path(), *k)).collect();
+        for entry in &manifest.entries {
+            let Some(path) = entry.path() else {
+                continue;
+            };
+            let parent = path.parent().unwrap_or(Path::new("")).to_path_buf();
+            if path == _filedates && !filedates_listed && manifest.filedates.is_some() {
+                let listing = listings.entry(parent).or_default();
+                listing.push((path, CreateKind::Filedates, Some(entry)));
+                filedates_listed = true;
+                continue;
+            }
+            let kind = if entry.dir {
+                CreateKind::Dir
+            } else {
+                CreateKind::File
+            };
+            if kinds.get(path.as_path()) == Some(&kind) && listed.insert(path.clone()) {
+                listings
+                    .entry(parent)
+                    .or_default()
+                    .push((path, kind, Some(entry)));
+            }
+        }
+    }
+    for (path, kind) in on_disk {
+        if !listed.contains(&path) {
+            let parent = path.parent().unwrap_or(Path::new("")).to_path_buf();
+            listings.entry(parent).or_default().push((path, kind, None));
+        }
+    }
+    if !filedates_listed {
+        let listing = listings.entry(PathBuf::new()).or_default();
+        listing.push((_filedates.to_path_buf(), CreateKind::Filedates, None));
+    }
+
+    fn visit<'a>(
+        dir: PathBuf,
+        listings: &mut HashMap<PathBuf, Listing<'a>>,
+        order: &mut Listing<'a>,
+    ) {
+        for (path, kind, entry) in listings.remove(&dir).unwrap_or_default() {
+            if kind == CreateKind::Dir {
+                visit(path, listings, order);
+            } else {
+                order.push((path, kind, entry));
+            }
+        }
+        order.push((dir, CreateKind::Dir, None));
+    }
+
+    let mut order = vec![];
+    visit(PathBuf::new(), &mut listings, &mut order);
+    Ok(order)
+}
+
 // vim: fdm=marker
@@ -0,0 +1,331 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use walkdir::WalkDir;
+
+use crate::progress::{self, Progress, ProgressEntry};
+use crate::walk::{walk, HpkIter};
+use crate::{copy, process_filedates, write_extracted};
+use crate::{DirEntry, ExtractOptions, HpkResult, NameEncoding, MANIFEST_NAME};
+
+/// Merged view of several archives and loose directories
+///
+/// The layers are mounted in the order they are added and the files of a later
+/// layer override the files with the same path of the earlier layers, like the
+/// packs of a game and the mods loaded after them.
+///
+/// The `_filedates` files of the archives are not part of the merged view.
+///
+/// ```no_run
+/// # fn main() -> Result<(), hpk::HpkError> {
+/// let mut overlay = hpk::Overlay::new();
+/// overlay.add_archive("Packs/Data.hpk")?;
+/// overlay.add_dir("Mods/MyMod")?;
+///
+/// for entry in overlay.entries() {
+///     let layer = overlay.layer_path(entry.layer());
+///     println!("{} <- {}", entry.path().display(), layer.display());
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Overlay {
+    layers: Vec<Layer>,
+    entries: BTreeMap<PathBuf, OverlayEntry>,
+    /// Directories of all layers
+    dirs: BTreeSet<PathBuf>,
+    encoding: NameEncoding,
+}
+
+struct Layer {
+    path: PathBuf,
+    archive: Option<HpkIter>,
+    filedates: Option<DirEntry>,
+}
+
+/// Effective file of an [`Overlay`]
+pub struct OverlayEntry {
+    path: PathBuf,
+    layer: usize,
+    /// Earlier layers with a file of the same path
+    overrides: Vec<usize>,
+    /// Member of an archive layer
+    dent: Option<DirEntry>,
+}
+
+impl OverlayEntry {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Index of the layer the file comes from
+    pub fn layer(&self) -> usize {
+        self.layer
+    }
+
+    /// Indices of the earlier layers whose file is overridden
+    pub fn overrides(&self) -> &[usize] {
+        &self.overrides
+    }
+}
+
+impl Overlay {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the encoding of the entry names for the archives added afterwards
+    pub fn set_name_encoding(&mut self, encoding: NameEncoding) {
+        self.encoding = encoding;
+    }
+
+    /// Mounts the archive on top of the previous layers
+    pub fn add_archive<P: AsRef<Path>>(&mut self, file: P) -> HpkResult<()> {
+        self.add_archive_matching(file, |_| true)
+    }
+
+    /// Mounts the entries of the archive that match the filter
+    ///
+    /// The parent directories of the matching files are mounted as well.
+    pub(crate) fn add_archive_matching<P, F>(&mut self, file: P, filter: F) -> HpkResult<()>
+    where
+        P: AsRef<Path>,
+        F: Fn(&Path) -> bool,
+    {
+        let mut walk = walk(file.as_ref())?;
+        walk.set_name_encoding(self.encoding);
+        let layer = self.layers.len();
+        let _filedates = Path::new("_filedates");
+
+        let mut files = vec![];
+        let mut filedates = None;
+        for entry in walk.by_ref() {
+            let entry = entry?;
+            if entry.is_dir() {
+                if entry.depth() > 0 && filter(entry.path()) {
+                    self.dirs.insert(entry.path().to_path_buf());
+                }
+            } else if entry.depth() == 1 && entry.path() == _filedates {
+                filedates = Some(entry);
+            } else if filter(entry.path()) {
+                let parents = entry.path().ancestors().skip(1);
+                let parents = parents.filter(|p| !p.as_os_str().is_empty());
+                self.dirs.extend(parents.map(Path::to_path_buf));
+                files.push(entry);
+            }
+        }
+        self.layers.push(Layer {
+            path: file.as_ref().to_path_buf(),
+            archive: Some(walk),
+            filedates,
+        });
+        for dent in files {
+            self.insert(layer, dent.path().to_path_buf(), Some(dent));
+        }
+        Ok(())
+    }
+
+    /// Mounts the files of the directory on top of the previous layers
+    pub fn add_dir<P: AsRef<Path>>(&mut self, dir: P) -> HpkResult<()> {
+        let dir = dir.as_ref();
+        let layer = self.layers.len();
+
+        let mut files = vec![];
+        for entry in WalkDir::new(dir).min_depth(1) {
+            let entry = entry?;
+            let path = entry
+                .path()
+                .strip_prefix(dir)
+                .expect("entry below the directory");
+            if entry.file_type().is_dir() {
+                self.dirs.insert(path.to_path_buf());
+                continue;
+            }
+            if entry.depth() == 1 && path == Path::new(MANIFEST_NAME) {
+                continue;
+            }
+            files.push(path.to_path_buf());
+        }
+        self.layers.push(Layer {
+            path: dir.to_path_buf(),
+            archive: None,
+            filedates: None,
+        });
+        for path in files {
+            self.insert(layer, path, None);
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, layer: usize, path: PathBuf, dent: Option<DirEntry>) {
+        let overrides = match self.entries.remove(&path) {
+            Some(mut prev) => {
+                prev.overrides.push(prev.layer);
+                prev.overrides
+            }
+            None => vec![],
+        };
+        let entry = OverlayEntry {
+            path: path.clone(),
+            layer,
+            overrides,
+            dent,
+        };
+        self.entries.insert(path, entry);
+    }
+
+    /// Returns the path of the archive or directory of the layer
+    ///
+    /// # Panics
+    ///
+    /// Panics if the layer doesn't exist.
+    pub fn layer_path(&self, layer: usize) -> &Path {
+        &self.layers[layer].path
+    }
+
+    /// Returns the effective files sorted by their path
+    pub fn entries(&self) -> impl Iterator<Item = &OverlayEntry> {
+        self.entries.values()
+    }
+
+    /// Returns the effective file of the path
+    pub fn get<P: AsRef<Path>>(&self, path: P) -> Option<&OverlayEntry> {
+        self.entries.get(path.as_ref())
+    }
+
+    /// Returns the directories of all layers sorted by their path
+    pub fn dirs(&self) -> &BTreeSet<PathBuf> {
+        &self.dirs
+    }
+
+    /// Reads the data of the file like it's stored in its archive or directory
+    ///
+    /// Compressed archive members are passed like [`HpkIter::read_file`] does, use
+    /// [`copy_file`] for the decompressed data.
+    ///
+    /// [`copy_file`]: Overlay::copy_file
+    pub fn read_file<F>(&self, entry: &OverlayEntry, op: F) -> HpkResult<()>
+    where
+        F: FnOnce(&mut dyn Read) -> HpkResult<()>,
+    {
+        let layer = &self.layers[entry.layer];
+        match (&layer.archive, &entry.dent) {
+            (Some(archive), Some(dent)) => archive.read_file(dent, |mut r| op(&mut r)),
+            _ => op(&mut File::open(layer.path.join(&entry.path))?),
+        }
+    }
+
+    /// Returns the size of the data like it's stored in the archive
+    pub(crate) fn raw_len(&self, entry: &OverlayEntry) -> HpkResult<u64> {
+        let layer = &self.layers[entry.layer];
+        match (&layer.archive, &entry.dent) {
+            (Some(archive), Some(dent)) => {
+                let mut len = 0;
+                archive.read_file(dent, |r| {
+                    len = r.len();
+                    Ok(())
+                })?;
+                Ok(len)
+            }
+            _ => Ok(layer.path.join(&entry.path).metadata()?.len()),
+        }
+    }
+
+    /// Returns the decompressed `_filedates` file of an archive layer
+    pub(crate) fn read_filedates(&self, layer: usize) -> HpkResult<Option<Vec<u8>>> {
+        let layer = &self.layers[layer];
+        let (Some(archive), Some(dent)) = (&layer.archive, &layer.filedates) else {
+            return Ok(None);
+        };
+        let mut buf = vec![];
+        archive.read_file(dent, |mut r| {
+            copy(&mut r, &mut buf)?;
+            Ok(())
+        })?;
+        Ok(Some(buf))
+    }
+
+    /// Writes the data of the file, compressed archive members are decompressed
+    pub fn copy_file<W: Write>(&self, entry: &OverlayEntry, w: &mut W) -> HpkResult<u64> {
+        let layer = &self.layers[entry.layer];
+        match (&layer.archive, &entry.dent) {
+            (Some(archive), Some(dent)) => {
+                let mut n = 0;
+                archive.read_file(dent, |mut r| {
+                    n = copy(&mut r, w)?;
+                    Ok(())
+                })?;
+                Ok(n)
+            }
+            _ => {
+                let mut file = File::open(layer.path.join(&entry.path))?;
+                Ok(io::copy(&mut file, w)?)
+            }
+        }
+    }
+
+    /// Extracts the effective files and the directories of all layers
+    ///
+    /// The files are written like [`extract`] writes the members of an archive. The
+    /// `_filedates` file of an archive layer sets the modification times of the files
+    /// coming from that layer unless [`ExtractOptions::skip_filedates`] is set.
+    /// [`ExtractOptions::write_manifest`] is not supported and ignored.
+    ///
+    /// [`extract`]: crate::extract
+    pub fn extract<P: AsRef<Path>>(&self, options: &ExtractOptions, dest: P) -> HpkResult<()> {
+        let result = self.extract_files(options, dest.as_ref());
+        progress::report_error(options.hooks(), result)
+    }
+
+    fn extract_files(&self, options: &ExtractOptions, dest: &Path) -> HpkResult<()> {
+        let hooks = options.hooks();
+        let entries: Vec<_> = self
+            .entries()
+            .filter(|e| options.matches(e.path()))
+            .collect();
+        let mut progress = Progress {
+            total_files: entries.len() as u64,
+            ..Progress::default()
+        };
+        if options.hooks.is_some() {
+            for entry in &entries {
+                progress.total_bytes += self.raw_len(entry)?;
+            }
+        }
+
+        for dir in self.dirs.iter().filter(|d| options.matches(d)) {
+            fs::create_dir_all(dest.join(dir))?;
+        }
+        for entry in entries {
+            let path = dest.join(entry.path());
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            progress::check(hooks.entry_start(entry.path()))?;
+            let stored = self.raw_len(entry)?;
+            let (inflated, _) = write_extracted(options, &path, entry.path(), |mut w| {
+                self.copy_file(entry, &mut w)
+            })?;
+            if options.verbose {
+                println!("{} ({} -> {} bytes)", path.display(), stored, inflated);
+            }
+            let entry = ProgressEntry::new(entry.path(), inflated, stored);
+            progress::file_done(hooks, &mut progress, entry, stored)?;
+        }
+
+        if !options.skip_filedates {
+            for layer in 0..self.layers.len() {
+                let Some(buf) = self.read_filedates(layer)? else {
+                    continue;
+                };
+                // the files overridden by a later layer keep the times of that layer
+                let of_layer = |path: &Path| self.get(path).map_or(true, |e| e.layer == layer);
+                process_filedates(dest, self.encoding, &buf, of_layer)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,240 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::ControlFlow;
+use std::path::Path;
+
+use crate::{HpkError, HpkResult};
+
+/// Callbacks of [`create`], [`extract`] and [`extract_to_tar`]
+///
+/// Returning `ControlFlow::Break(())` from a callback cancels the operation with
+/// [`HpkError::Cancelled`]. A failed or cancelled [`create`] removes the output archive
+/// and [`extract`] removes the partially written file.
+///
+/// [`compress_with_progress`] and [`compress_file_with_progress`] report the number of
+/// read bytes to a callback instead.
+///
+/// [`create`]: crate::create
+/// [`extract`]: crate::extract
+/// [`extract_to_tar`]: crate::extract_to_tar
+/// [`compress_with_progress`]: crate::compress_with_progress
+/// [`compress_file_with_progress`]: crate::compress_file_with_progress
+pub trait Hooks {
+    /// Called before the data of a file is processed
+    fn entry_start(&self, _path: &Path) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+
+    /// Called while the data of a file is written with the number of bytes written so far
+    fn bytes(&self, _path: &Path, _written: u64) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+
+    /// Called after every processed file, also in the [`ProgressStage::Measure`] stage
+    fn entry_done(&self, _progress: &Progress<'_>) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+
+    /// Called with the error that aborts the operation, except for [`HpkError::Cancelled`]
+    ///
+    /// The extraction also reports the [`HpkError::UnsafeDirEntryName`] of the members
+    /// skipped by [`ExtractOptions::skip_unsafe_paths`] without aborting.
+    ///
+    /// [`ExtractOptions::skip_unsafe_paths`]: crate::ExtractOptions::skip_unsafe_paths
+    fn error(&self, _error: &HpkError) {}
+}
+
+/// The hooks of options without hooks
+pub(crate) struct NoHooks;
+
+impl Hooks for NoHooks {}
+
+/// Hooks of [`CreateOptions::set_progress`] and [`ExtractOptions::set_progress`]
+///
+/// [`CreateOptions::set_progress`]: crate::CreateOptions::set_progress
+/// [`ExtractOptions::set_progress`]: crate::ExtractOptions::set_progress
+pub(crate) struct ProgressFn<F>(pub F);
+
+impl<F: Fn(&Progress<'_>)> Hooks for ProgressFn<F> {
+    fn entry_done(&self, progress: &Progress<'_>) -> ControlFlow<()> {
+        (self.0)(progress);
+        ControlFlow::Continue(())
+    }
+}
+
+/// Progress of [`create`], [`extract`] and [`extract_to_tar`]
+///
+/// The callback set with [`CreateOptions::set_progress`] or [`ExtractOptions::set_progress`]
+/// is called after every processed file. A compressed archive reports every file twice,
+/// first in the [`ProgressStage::Measure`] stage.
+///
+/// [`create`]: crate::create
+/// [`extract`]: crate::extract
+/// [`extract_to_tar`]: crate::extract_to_tar
+/// [`CreateOptions::set_progress`]: crate::CreateOptions::set_progress
+/// [`ExtractOptions::set_progress`]: crate::ExtractOptions::set_progress
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Progress<'a> {
+    pub stage: ProgressStage,
+    /// The file that was just processed
+    pub entry: Option<ProgressEntry<'a>>,
+    pub files: u64,
+    pub total_files: u64,
+    /// Processed input bytes of the stage
+    ///
+    /// These are the bytes of the input files for [`create`] and the stored bytes of
+    /// the archive members for [`extract`].
+    ///
+    /// [`create`]: crate::create
+    /// [`extract`]: crate::extract
+    pub bytes: u64,
+    pub total_bytes: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProgressStage {
+    /// Measuring the uncompressed archive in a dry run before the whole archive is
+    /// compressed while the members are written
+    Measure,
+    /// Writing or reading the archive members
+    #[default]
+    Entries,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ProgressEntry<'a> {
+    pub path: &'a Path,
+    /// Size of the uncompressed data
+    pub inflated: u64,
+    /// Size of the data in the archive
+    pub stored: u64,
+}
+
+impl<'a> ProgressEntry<'a> {
+    pub(crate) fn new(path: &'a Path, inflated: u64, stored: u64) -> Self {
+        ProgressEntry {
+            path,
+            inflated,
+            stored,
+        }
+    }
+}
+
+/// Returns [`HpkError::Cancelled`] if the hook breaks
+pub(crate) fn check(flow: ControlFlow<()>) -> HpkResult<()> {
+    match flow {
+        ControlFlow::Continue(()) => Ok(()),
+        ControlFlow::Break(()) => Err(HpkError::Cancelled),
+    }
+}
+
+/// Reports the error of a failed operation
+pub(crate) fn report_error<T>(hooks: &dyn Hooks, result: HpkResult<T>) -> HpkResult<T> {
+    match &result {
+        Err(HpkError::Cancelled) | Ok(_) => {}
+        Err(err) => hooks.error(err),
+    }
+    result
+}
+
+/// Counts the processed file and reports the progress
+pub(crate) fn file_done(
+    hooks: &dyn Hooks,
+    progress: &mut Progress<'_>,
+    entry: ProgressEntry<'_>,
+    bytes: u64,
+) -> HpkResult<()> {
+    progress.files += 1;
+    progress.bytes += bytes;
+    check(hooks.entry_done(&Progress {
+        entry: Some(entry),
+        ..*progress
+    }))
+}
+
+/// Reports the written bytes of a file to [`Hooks::bytes`]
+pub(crate) struct HookWriter<'a, W> {
+    inner: W,
+    hooks: &'a dyn Hooks,
+    path: &'a Path,
+    written: u64,
+}
+
+impl<'a, W: Write> HookWriter<'a, W> {
+    pub(crate) fn new(inner: W, hooks: &'a dyn Hooks, path: &'a Path) -> Self {
+        HookWriter {
+            inner,
+            hooks,
+            path,
+            written: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for HookWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.written += n as u64;
+        if self.hooks.bytes(self.path, self.written).is_break() {
+            return Err(io::Error::new(io::ErrorKind::Other, Cancelled));
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for HookWriter<'_, W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Reader that reports the read bytes of a file to the hooks
+pub(crate) struct HookReader<'a, R> {
+    inner: R,
+    hooks: &'a dyn Hooks,
+    path: &'a Path,
+    read: u64,
+}
+
+impl<'a, R: Read> HookReader<'a, R> {
+    pub(crate) fn new(inner: R, hooks: &'a dyn Hooks, path: &'a Path) -> Self {
+        HookReader {
+            inner,
+            hooks,
+            path,
+            read: 0,
+        }
+    }
+
+    pub(crate) fn bytes_read(&self) -> u64 {
+        self.read
+    }
+}
+
+impl<R: Read> Read for HookReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if n > 0 && self.hooks.bytes(self.path, self.read).is_break() {
+            return Err(io::Error::new(io::ErrorKind::Other, Cancelled));
+        }
+        Ok(n)
+    }
+}
+
+/// Marks the I/O error of a cancelled write, converted to [`HpkError::Cancelled`]
+#[derive(Debug)]
+pub(crate) struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("cancelled")
+    }
+}
+
+impl Error for Cancelled {}
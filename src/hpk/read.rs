@@ -200,6 +200,7 @@ mod tests {
     // }}}
 
     // trait PrintState {{{
+    #[allow(dead_code)]
     trait PrintState {
         fn print_state(&mut self);
     }
@@ -226,7 +227,7 @@ mod tests {
 
     #[test]
     fn fragmented_reader_read() {
-        let sample = vec![
+        let sample = [
             (10, 12, 0x11),
             (32, 20, 0x22),
             (60, 35, 0x33),
@@ -270,7 +271,7 @@ mod tests {
 
     #[test]
     fn fragmented_reader_read_exact() {
-        let sample = vec![
+        let sample = [
             (10, 12, 0x11),
             (32, 20, 0x22),
             (60, 35, 0x33),
@@ -304,7 +305,7 @@ mod tests {
 
     #[test]
     fn fragmented_reader_seek() {
-        let sample = vec![
+        let sample = [
             (10, 12, 0x11),
             (32, 20, 0x22),
             (60, 35, 0x33),
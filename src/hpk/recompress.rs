@@ -0,0 +1,282 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, Cursor, Read, Seek, Write};
+use std::path::Path;
+
+use crate::atomic::AtomicFile;
+use crate::read::FragmentedReader;
+use crate::walk::{walk, HpkIter};
+use crate::write::KEPT_DATA_LIMIT;
+use crate::write::{changed_input, ChunkTables, ChunkWriter, Compressed, SizeCounter};
+use crate::{archive_compression, copy, member_compression, Header, HpkWriter, NameEncoding};
+use crate::{CompressOptions, Compression, CompressionHeader, DirEntry, HpkError, HpkResult};
+
+#[derive(Default)]
+pub struct RecompressOptions {
+    compress_options: CompressOptions,
+    extensions: Option<Vec<String>>,
+    name_encoding: NameEncoding,
+    verbose: bool,
+}
+
+impl RecompressOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the encoder of the compressed members, `Compression::None` stores every
+    /// member uncompressed
+    pub fn set_codec(&mut self, codec: Compression) {
+        self.compress_options.compressor = codec;
+    }
+
+    pub fn with_chunk_size(&mut self, chunk_size: u32) {
+        self.compress_options.chunk_size = chunk_size;
+    }
+
+    /// Compresses the files with the extensions instead of the members that are
+    /// compressed in the input archive
+    pub fn with_extensions(&mut self, ext: Vec<String>) {
+        self.extensions = Some(ext);
+    }
+
+    pub fn set_name_encoding(&mut self, encoding: NameEncoding) {
+        self.name_encoding = encoding;
+    }
+
+    /// Lists every file with its stored size before and after
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+
+    fn compresses(&self, path: &Path, compressed: bool) -> bool {
+        if !self.compress_options.compressor.is_compressed() {
+            return false;
+        }
+        let Some(extensions) = &self.extensions else {
+            return compressed;
+        };
+        let ext = path
+            .extension()
+            .and_then(OsStr::to_str)
+            .map_or(String::new(), str::to_ascii_lowercase);
+        extensions.contains(&ext)
+    }
+}
+
+/// Rewrites every member of the archive with new compression settings
+///
+/// The entries keep their paths and the order of the directory listings, the
+/// `_filedates` file and the other uncompressed members are copied unmodified.
+/// Members that are already compressed with the encoder and chunk size are copied
+/// as they're stored. An archive that is compressed as a whole is compressed with
+/// the new settings as well, without a temporary copy of the uncompressed archive.
+/// A compressed member whose data doesn't match the length of its compression header
+/// fails with [`HpkError::InvalidCompressionHeader`].
+///
+/// The output is written to a temp file next to it, so it can be the input archive.
+pub fn recompress<P: AsRef<Path>>(options: &RecompressOptions, file: P, dest: P) -> HpkResult<()> {
+    let file = file.as_ref();
+    let whole = archive_compression(file)?.is_some();
+    let mut walk = walk(file)?;
+    walk.set_name_encoding(options.name_encoding);
+    let entries = walk.by_ref().collect::<HpkResult<Vec<_>>>()?;
+
+    // the entries of the directories in the order of their listings
+    let mut listings: HashMap<&Path, Vec<&DirEntry>> = HashMap::new();
+    for entry in entries.iter().filter(|e| e.depth() > 0) {
+        let parent = entry.path().parent().unwrap_or(Path::new(""));
+        listings.entry(parent).or_default().push(entry);
+    }
+
+    let mut out = AtomicFile::new(dest.as_ref())?;
+    if whole && options.compress_options.compressor.is_compressed() {
+        // A dry run measures the archive and records the compressed members like
+        // `create` does, then the archive is compressed while it's written.
+        let mut tables = ChunkTables::record();
+        let writer = HpkWriter::new(SizeCounter::default())?;
+        let (_, header) = write_entries(options, &walk, &listings, &mut tables, writer)?;
+
+        let mut tables = tables.replay();
+        let length = header.fragmented_filesystem_offset + header.fragmented_filesystem_length;
+        let w = ChunkWriter::new(out.as_file_mut(), options.compress_options, length)?;
+        let writer = HpkWriter::with_header(w, header)?;
+        let (w, _) = write_entries(options, &walk, &listings, &mut tables, writer)?;
+        w.finish()?;
+    } else {
+        let writer = HpkWriter::new(out.as_file_mut())?;
+        write_entries(options, &walk, &listings, &mut ChunkTables::Rewrite, writer)?;
+    }
+    out.commit()
+}
+
+/// Writes the directories depth-first with the contents of a directory before its listing
+fn write_entries<W: Write + Seek>(
+    options: &RecompressOptions,
+    walk: &HpkIter,
+    listings: &HashMap<&Path, Vec<&DirEntry>>,
+    tables: &mut ChunkTables,
+    mut writer: HpkWriter<W>,
+) -> HpkResult<(W, Header)> {
+    writer.set_name_encoding(options.name_encoding);
+
+    let mut stack = vec![(Path::new(""), 0)];
+    while let Some((dir, next)) = stack.pop() {
+        let listing = listings.get(dir).map(Vec::as_slice).unwrap_or_default();
+        match listing.get(next) {
+            Some(entry) if entry.is_dir() => {
+                stack.push((dir, next + 1));
+                stack.push((entry.path(), 0));
+            }
+            Some(entry) => {
+                stack.push((dir, next + 1));
+                walk.read_file(entry, |r| {
+                    recompress_file(options, tables, &mut writer, entry, r)
+                })?;
+            }
+            None => writer.add_dir(dir)?,
+        }
+    }
+    writer.finish_with_header()
+}
+
+fn recompress_file<W: Write + Seek>(
+    options: &RecompressOptions,
+    tables: &mut ChunkTables,
+    writer: &mut HpkWriter<W>,
+    entry: &DirEntry,
+    mut r: FragmentedReader<&File>,
+) -> HpkResult<()> {
+    let path = entry.path();
+    let current = member_compression(&mut r)?;
+    let compress = options.compresses(path, current.is_some());
+    let compress_options = options.compress_options;
+    let dry_run = matches!(tables, ChunkTables::Record { .. });
+
+    let unchanged = match &current {
+        Some(c) => {
+            compress
+                && c.codec == compress_options.compressor
+                && c.chunk_size == compress_options.chunk_size
+        }
+        None => !compress,
+    };
+    let stored = if unchanged && dry_run {
+        // the dry run only needs the size of the member
+        let len = r.len();
+        writer.add_file_with(path, |w| Ok(io::copy(&mut io::repeat(0).take(len), w)?))?
+    } else if unchanged {
+        writer.add_file(path, &mut r)?
+    } else if compress {
+        let length = match current {
+            Some(_) => {
+                let hdr = CompressionHeader::read_from(r.len(), &mut r)?;
+                r.rewind()?;
+                u64::from(hdr.inflated_length)
+            }
+            None => r.len(),
+        };
+        writer.add_file_with(path, |w| {
+            write_compressed(compress_options, tables, length, &mut r, w)
+        })?
+    } else {
+        writer.add_file_with(path, |w| copy(&mut r, w))?
+    };
+
+    if options.verbose && !dry_run {
+        println!("{} ({} -> {} bytes)", path.display(), r.len(), stored);
+    }
+    Ok(())
+}
+
+/// Compresses the member data, the dry run of a compressed archive records the
+/// offset table and keeps the data in memory up to [`KEPT_DATA_LIMIT`]
+fn write_compressed<W: Write + Seek>(
+    options: CompressOptions,
+    tables: &mut ChunkTables,
+    length: u64,
+    r: &mut FragmentedReader<&File>,
+    w: &mut W,
+) -> HpkResult<u64> {
+    match tables {
+        ChunkTables::Record { files, kept } => {
+            let (table, data) = if *kept + length <= KEPT_DATA_LIMIT {
+                let mut cw = ChunkWriter::new(Cursor::new(vec![]), options, length)?;
+                inflate(r, length, &mut cw)?;
+                let (buf, table) = cw.finish_with_table()?;
+                (table, Some(buf.into_inner()))
+            } else {
+                let mut cw = ChunkWriter::new(SizeCounter::default(), options, length)?;
+                inflate(r, length, &mut cw)?;
+                (cw.finish_with_table()?.1, None)
+            };
+            *kept += data.as_ref().map_or(0, |d| d.len() as u64);
+            let len = table.len;
+            io::copy(&mut io::repeat(0).take(len), w)?;
+            files.push_back(Some(Compressed { table, data }));
+            Ok(len)
+        }
+        ChunkTables::Replay(files) => match files.pop_front().flatten() {
+            Some(Compressed {
+                table,
+                data: Some(data),
+            }) => {
+                w.write_all(&data)?;
+                Ok(table.len)
+            }
+            Some(Compressed { table, data: None }) => {
+                let mut cw = ChunkWriter::with_table(w, options, length, table)?;
+                inflate(r, length, &mut cw)?;
+                Ok(cw.finish_with_table()?.1.len)
+            }
+            None => Err(changed_input()),
+        },
+        ChunkTables::Rewrite => {
+            let mut cw = ChunkWriter::new(w, options, length)?;
+            inflate(r, length, &mut cw)?;
+            Ok(cw.finish_with_table()?.1.len)
+        }
+    }
+}
+
+/// Copies the member data and fails if the decompressed size differs from the
+/// length of its compression header
+///
+/// The data beyond the length is discarded, so the input archive is reported as
+/// corrupt instead of changed while it's read.
+fn inflate<W: Write>(r: &mut FragmentedReader<&File>, length: u64, w: &mut W) -> HpkResult<()> {
+    let mut w = Inflated {
+        w,
+        length,
+        written: 0,
+    };
+    copy(r, &mut w)?;
+    if w.written != length {
+        return Err(HpkError::InvalidCompressionHeader);
+    }
+    Ok(())
+}
+
+/// Counts the written data and passes it on up to the expected length
+struct Inflated<W> {
+    w: W,
+    length: u64,
+    written: u64,
+}
+
+impl<W: Write> Write for Inflated<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let remaining = self.length.saturating_sub(self.written);
+        let n = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        self.w.write_all(&buf[..n])?;
+        self.written += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
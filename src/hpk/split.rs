@@ -0,0 +1,316 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use crate::overlay::Overlay;
+use crate::write::{contents_first, write_archive};
+use crate::{HpkError, HpkResult, NameEncoding, HEADER_LENGTH};
+
+#[derive(Default)]
+pub struct SplitOptions {
+    max_size: Option<u64>,
+    name_encoding: NameEncoding,
+    verbose: bool,
+}
+
+impl SplitOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Splits the archive into parts of at most `max_size` bytes instead of one
+    /// archive per top-level directory
+    ///
+    /// A file that doesn't fit into an empty part is written to a part of its own.
+    pub fn with_max_size(&mut self, max_size: u64) {
+        self.max_size = Some(max_size);
+    }
+
+    pub fn set_name_encoding(&mut self, encoding: NameEncoding) {
+        self.name_encoding = encoding;
+    }
+
+    /// Lists every file and the archive it's written to
+    pub fn set_verbose(&mut self, verbose: bool) {
+        self.verbose = verbose;
+    }
+}
+
+/// Splits the archive into several archives in the destination directory
+///
+/// Every top-level directory is written to an archive named after the directory and
+/// the files of the root directory to an archive named after the input archive.
+/// With [`SplitOptions::with_max_size`] the parts are named `<name>.1.hpk`,
+/// `<name>.2.hpk` and so on. Two parts named alike, ignoring the case, fail with
+/// [`HpkError::SplitConflict`], e.g. the files of the root directory of `pack.hpk` and
+/// the top-level directory `pack`.
+///
+/// The member data is copied as it's stored, compressed files are not recompressed.
+/// A compressed input archive is split into uncompressed archives. Every part gets
+/// the lines of the `_filedates` file for its entries.
+///
+/// Returns the paths of the written archives.
+pub fn split<P: AsRef<Path>>(options: &SplitOptions, file: P, dest: P) -> HpkResult<Vec<PathBuf>> {
+    let file = file.as_ref();
+    let mut overlay = Overlay::new();
+    overlay.set_name_encoding(options.name_encoding);
+    overlay.add_archive(file)?;
+
+    let name = file.file_stem().unwrap_or_default().to_string_lossy();
+    let filedates = overlay.read_filedates(0)?;
+    let filedates = filedates.map(|buf| Filedates::parse(&overlay, options.name_encoding, &buf));
+
+    let parts = match options.max_size {
+        Some(max_size) => {
+            let sizes = Sizes {
+                overlay: &overlay,
+                encoding: options.name_encoding,
+                filedates: filedates.as_ref(),
+            };
+            split_by_size(&sizes, &name, max_size)?
+        }
+        None => split_by_dir(&overlay, &name)?,
+    };
+
+    fs::create_dir_all(dest.as_ref())?;
+    let mut written = vec![];
+    for part in parts {
+        let path = dest.as_ref().join(&part.name);
+        let part_filedates = filedates.as_ref().map(|f| f.select(&part));
+        write_archive(
+            &path,
+            options.name_encoding,
+            part.files.iter().map(PathBuf::as_path),
+            part.dirs.iter().map(PathBuf::as_path),
+            part_filedates.as_deref(),
+            |writer, file| {
+                let entry = overlay.get(file).expect("file of the archive");
+                overlay.read_file(entry, |r| {
+                    writer.add_file(file, r)?;
+                    Ok(())
+                })?;
+                if options.verbose {
+                    println!("{} ({})", file.display(), path.display());
+                }
+                Ok(())
+            },
+        )?;
+        written.push(path);
+    }
+    Ok(written)
+}
+
+/// Files and directories written to one archive
+#[derive(Default)]
+struct Part {
+    name: String,
+    files: Vec<PathBuf>,
+    dirs: Vec<PathBuf>,
+}
+
+impl Part {
+    fn is_empty(&self) -> bool {
+        self.files.is_empty() && self.dirs.is_empty()
+    }
+}
+
+fn split_by_dir(overlay: &Overlay, name: &str) -> HpkResult<Vec<Part>> {
+    // the parts are keyed by their name and whether they hold the files of the root
+    let mut parts: BTreeMap<(String, bool), Part> = BTreeMap::new();
+    let mut part = |path: &Path, is_dir: bool| {
+        let mut comps = path.components();
+        let first = comps.next().expect("path below the root");
+        // the files of the root directory are written to an archive named after the input
+        let key = match comps.next() {
+            Some(_) => (first.as_os_str().to_string_lossy().into_owned(), false),
+            None if is_dir => (first.as_os_str().to_string_lossy().into_owned(), false),
+            None => (name.to_string(), true),
+        };
+        let part = parts.entry(key).or_insert_with_key(|(key, _)| Part {
+            name: format!("{}.hpk", key),
+            ..Part::default()
+        });
+        if is_dir {
+            part.dirs.push(path.to_path_buf());
+        } else {
+            part.files.push(path.to_path_buf());
+        }
+    };
+    for entry in overlay.entries() {
+        part(entry.path(), false);
+    }
+    for dir in overlay.dirs() {
+        part(dir, true);
+    }
+
+    // the file systems of Windows ignore the case of the names
+    let mut names = HashSet::new();
+    for part in parts.values() {
+        if !names.insert(part.name.to_lowercase()) {
+            return Err(HpkError::SplitConflict(PathBuf::from(&part.name)));
+        }
+    }
+    Ok(parts.into_values().collect())
+}
+
+fn split_by_size(sizes: &Sizes<'_>, name: &str, max_size: u64) -> HpkResult<Vec<Part>> {
+    let overlay = sizes.overlay;
+    // the other directories are written as empty directories
+    let mut with_files = HashSet::new();
+    for entry in overlay.entries() {
+        with_files.extend(entry.path().ancestors().skip(1));
+    }
+
+    let mut parts = vec![];
+    let mut part = Part::default();
+    let mut size = sizes.empty_archive();
+    let mut dirs = HashSet::new();
+    let files = overlay.entries().map(|e| e.path());
+    for (path, is_dir) in contents_first(files, overlay.dirs().iter().map(PathBuf::as_path)) {
+        if is_dir && with_files.contains(path.as_path()) {
+            continue;
+        }
+        let mut added = sizes.entry(&path, is_dir, &dirs)?;
+        if !part.is_empty() && size + added > max_size {
+            parts.push(mem::take(&mut part));
+            size = sizes.empty_archive();
+            dirs.clear();
+            added = sizes.entry(&path, is_dir, &dirs)?;
+        }
+        size += added;
+        for dir in path.ancestors().skip(1) {
+            if !dir.as_os_str().is_empty() {
+                dirs.insert(dir.to_path_buf());
+            }
+        }
+        if is_dir {
+            part.dirs.push(path);
+        } else {
+            part.files.push(path);
+        }
+    }
+    if !part.is_empty() {
+        parts.push(part);
+    }
+    for (i, part) in parts.iter_mut().enumerate() {
+        part.name = format!("{}.{}.hpk", name, i + 1);
+    }
+    Ok(parts)
+}
+
+/// Computes the sizes of the written archives
+struct Sizes<'a> {
+    overlay: &'a Overlay,
+    encoding: NameEncoding,
+    filedates: Option<&'a Filedates>,
+}
+
+impl Sizes<'_> {
+    /// Header and root directory fragment plus the `_filedates` entry
+    fn empty_archive(&self) -> u64 {
+        let mut size = u64::from(HEADER_LENGTH) + 8;
+        if self.filedates.is_some() {
+            size += self.dir_entry(Path::new("_filedates"));
+        }
+        size
+    }
+
+    /// Size of the entry and its parent directories that are not in `dirs` yet
+    fn entry(&self, path: &Path, is_dir: bool, dirs: &HashSet<PathBuf>) -> HpkResult<u64> {
+        let mut size = self.dir_entry(path);
+        if !is_dir {
+            let entry = self.overlay.get(path).expect("file of the archive");
+            size += self.overlay.raw_len(entry)?;
+        }
+        for dir in path.ancestors().skip(1) {
+            if !dir.as_os_str().is_empty() && !dirs.contains(dir) {
+                size += self.dir_entry(dir);
+            }
+        }
+        Ok(size)
+    }
+
+    /// Fragment, entry in the directory listing and `_filedates` lines of the path
+    fn dir_entry(&self, path: &Path) -> u64 {
+        let name = path.file_name().unwrap_or_default();
+        let name_len = match self.encoding.encode(name) {
+            Some(name) => name.len(),
+            None => name.len(),
+        };
+        let filedates = self.filedates.map(|f| f.len(path)).unwrap_or_default();
+        8 + 10 + name_len as u64 + filedates
+    }
+}
+
+/// Lines of a `_filedates` file by the path of the entry
+struct Filedates {
+    lines: Vec<Vec<u8>>,
+    paths: HashMap<PathBuf, Vec<usize>>,
+}
+
+impl Filedates {
+    /// Assigns the lines to the entries of the archive, the lines of other paths and
+    /// of paths that can't be decoded are dropped
+    ///
+    /// Grand Ages: Rome adds the basename of the original hpk file to the path, so the
+    /// path without its first component is tried if the path doesn't exist.
+    fn parse(overlay: &Overlay, encoding: NameEncoding, buf: &[u8]) -> Self {
+        let exists = |path: &Path| overlay.get(path).is_some() || overlay.dirs().contains(path);
+
+        let mut lines = vec![];
+        let mut paths: HashMap<PathBuf, Vec<usize>> = HashMap::new();
+        for line in buf.split(|b| *b == b'\n') {
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            let Some(pos) = line.iter().rposition(|b| *b == b'=') else {
+                continue;
+            };
+            let Some(path) = encoding.decode(&line[..pos]) else {
+                continue;
+            };
+            let path = path.replace('\\', "/");
+            let path = Path::new(&path);
+            let mut comps = path.components();
+            comps.next();
+            let path = match (exists(path), exists(comps.as_path())) {
+                (true, _) => path,
+                (false, true) => comps.as_path(),
+                (false, false) => continue,
+            };
+            paths
+                .entry(path.to_path_buf())
+                .or_default()
+                .push(lines.len());
+            lines.push(line.to_vec());
+        }
+        Filedates { lines, paths }
+    }
+
+    /// Size of the lines of the path
+    fn len(&self, path: &Path) -> u64 {
+        let lines = self.paths.get(path).into_iter().flatten();
+        lines.map(|&i| self.lines[i].len() as u64 + 1).sum()
+    }
+
+    /// Returns the `_filedates` file of the part
+    fn select(&self, part: &Part) -> Vec<u8> {
+        let mut paths = HashSet::new();
+        for path in part.files.iter().chain(&part.dirs) {
+            paths.extend(path.ancestors().filter(|p| !p.as_os_str().is_empty()));
+        }
+        let mut selected: Vec<usize> = paths
+            .into_iter()
+            .filter_map(|p| self.paths.get(p))
+            .flatten()
+            .copied()
+            .collect();
+        selected.sort_unstable();
+
+        let mut buf = vec![];
+        for i in selected {
+            buf.extend_from_slice(&self.lines[i]);
+            buf.push(b'\n');
+        }
+        buf
+    }
+}
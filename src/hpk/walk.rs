@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::ffi::OsStr;
 use std::fs::File;
 use std::io::prelude::*;
@@ -8,7 +9,7 @@ use std::path::PathBuf;
 
 use crate::read::FragmentedReader;
 use crate::{copy, get_compression};
-use crate::{DirEntry, Fragment, Header, HpkResult};
+use crate::{DirEntry, Fragment, Header, HpkError, HpkResult, NameEncoding};
 
 pub fn walk<P: AsRef<Path>>(file: P) -> HpkResult<HpkIter> {
     let file = file.as_ref().to_path_buf();
@@ -35,6 +36,25 @@ pub fn walk<P: AsRef<Path>>(file: P) -> HpkResult<HpkIter> {
     };
 
     let hdr = Header::read_from(&mut f)?;
+    let file_len = f.metadata()?.len();
+    let in_bounds = |offset: u64, length: Option<u64>| {
+        length
+            .and_then(|length| offset.checked_add(length))
+            .is_some_and(|end| end <= file_len)
+    };
+    if hdr.fragments_per_file == 0
+        || !in_bounds(
+            hdr.fragmented_filesystem_offset,
+            Some(hdr.fragmented_filesystem_length),
+        )
+        || !in_bounds(
+            hdr.fragments_residual_offset,
+            hdr.fragments_residual_count.checked_mul(8),
+        )
+    {
+        return Err(HpkError::InvalidHeader);
+    }
+
     let mut fragments_data = Cursor::new(vec![0; hdr.fragmented_filesystem_length as usize]);
 
     f.seek(SeekFrom::Start(hdr.fragmented_filesystem_offset))?;
@@ -56,6 +76,11 @@ pub fn walk<P: AsRef<Path>>(file: P) -> HpkResult<HpkIter> {
     let residual_count = hdr.fragments_residual_count;
     let residuals = Fragment::read_nth_from(residual_count as usize, &mut residual_data)?;
 
+    let valid = |f: &Fragment| in_bounds(f.offset, Some(f.length));
+    if !fragments.iter().flatten().chain(&residuals).all(valid) {
+        return Err(HpkError::InvalidFragment);
+    }
+
     Ok(HpkIter {
         file,
         f,
@@ -64,7 +89,9 @@ pub fn walk<P: AsRef<Path>>(file: P) -> HpkResult<HpkIter> {
         start: Some(DirEntry::new_root()),
         fragments,
         residuals,
+        encoding: NameEncoding::default(),
         stack_list: vec![],
+        visited: HashSet::new(),
     })
 }
 
@@ -76,11 +103,16 @@ pub struct HpkIter {
     start: Option<DirEntry>,
     pub fragments: Vec<Vec<Fragment>>,
     pub residuals: Vec<Fragment>,
+    encoding: NameEncoding,
     stack_list: Vec<DirList>,
+    /// Fragment indices of the traversed directories
+    visited: HashSet<usize>,
 }
 
 struct DirList {
-    entries: Vec<DirEntry>,
+    /// Fragment index of the directory
+    index: usize,
+    entries: Vec<HpkResult<DirEntry>>,
 }
 
 impl Iterator for HpkIter {
@@ -116,12 +148,20 @@ impl HpkIter {
         &self.header
     }
 
+    /// Sets the encoding of the entry names for the following directories
+    pub fn set_name_encoding(&mut self, encoding: NameEncoding) {
+        self.encoding = encoding;
+    }
+
     pub fn read_file<F>(&self, entry: &DirEntry, op: F) -> HpkResult<()>
     where
         F: FnOnce(FragmentedReader<&File>) -> HpkResult<()>,
     {
         if !entry.is_dir() {
-            let fragments = &self.fragments[entry.index()];
+            let fragments = self
+                .fragments
+                .get(entry.index())
+                .ok_or(HpkError::InvalidFragmentIndex)?;
             let r = FragmentedReader::new(&self.f, fragments);
             op(r)?;
         }
@@ -136,7 +176,25 @@ impl HpkIter {
     }
 
     fn push(&mut self, dent: &DirEntry) -> HpkResult<()> {
-        let fragment = &self.fragments[dent.index()][0];
+        // The stack contains the parent directories of the entry
+        if self
+            .stack_list
+            .iter()
+            .any(|list| list.index == dent.index())
+        {
+            return Err(HpkError::CyclicDirEntry(dent.path().to_path_buf()));
+        }
+        let fragment = self
+            .fragments
+            .get(dent.index())
+            .and_then(|fragments| fragments.first())
+            .ok_or(HpkError::InvalidFragmentIndex)?;
+        // Directories sharing their entries would be traversed again for every entry
+        // pointing to them, which grows exponentially with nested shared directories.
+        // Empty directories have nothing to traverse.
+        if fragment.length > 0 && !self.visited.insert(dent.index()) {
+            return Err(HpkError::DuplicateDirEntry(dent.path().to_path_buf()));
+        }
         let mut dir_entries = Cursor::new(vec![0; fragment.length as usize]);
 
         self.f.seek(SeekFrom::Start(fragment.offset))?;
@@ -144,10 +202,23 @@ impl HpkIter {
 
         let mut list = vec![];
         while dir_entries.position() < fragment.length {
-            let entry = DirEntry::read_from(dent.path(), dent.depth + 1, &mut dir_entries)?;
-            list.push(entry);
+            // Entries with invalid or unsafe names or invalid indices are reported by the
+            // iterator without stopping the traversal of the remaining entries.
+            let depth = dent.depth + 1;
+            match DirEntry::read_from(dent.path(), depth, self.encoding, &mut dir_entries) {
+                Ok(entry) if entry.index() >= self.fragments.len() => {
+                    list.push(Err(HpkError::InvalidFragmentIndex))
+                }
+                Err(err @ (HpkError::InvalidDirEntryName | HpkError::UnsafeDirEntryName(_))) => {
+                    list.push(Err(err))
+                }
+                entry => list.push(Ok(entry?)),
+            }
         }
-        self.stack_list.push(DirList { entries: list });
+        self.stack_list.push(DirList {
+            index: dent.index(),
+            entries: list,
+        });
         Ok(())
     }
 
@@ -163,7 +234,7 @@ impl Iterator for DirList {
         if self.entries.is_empty() {
             None
         } else {
-            Some(Ok(self.entries.remove(0)))
+            Some(self.entries.remove(0))
         }
     }
 }
@@ -0,0 +1,637 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::error::Error;
+use std::ffi::OsStr;
+use std::fmt;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::atomic::AtomicFile;
+use crate::{CompressOptions, CompressionHeader, DirEntry, Fragment, Header, HpkError, HpkResult};
+use crate::{NameEncoding, HEADER_LENGTH};
+
+/// Writes the entries of a new hpk archive
+///
+/// The entries are written in the order they are added. A directory listing is
+/// written by [`add_dir`] and must follow all entries of the directory like
+/// `WalkDir::contents_first` does. Directories that were not added explicitly
+/// are written by [`finish`].
+///
+/// [`add_dir`]: HpkWriter::add_dir
+/// [`finish`]: HpkWriter::finish
+pub struct HpkWriter<W: Write + Seek> {
+    w: W,
+    encoding: NameEncoding,
+    fragments: Vec<Fragment>,
+    root: Option<Fragment>,
+    /// Pending directory listings
+    dirs: HashMap<PathBuf, Vec<u8>>,
+    /// Header written up front by `with_header`
+    header: Option<Header>,
+}
+
+impl<W: Write + Seek> HpkWriter<W> {
+    pub fn new(mut w: W) -> HpkResult<Self> {
+        w.seek(SeekFrom::Start(u64::from(HEADER_LENGTH)))?;
+        Ok(HpkWriter {
+            w,
+            encoding: NameEncoding::default(),
+            fragments: vec![],
+            root: None,
+            dirs: HashMap::new(),
+            header: None,
+        })
+    }
+
+    /// Starts the archive with a header that is already known, e.g. from a dry run
+    ///
+    /// The writer doesn't seek back to write the header on [`finish`], which fails
+    /// if the written archive doesn't match the header.
+    ///
+    /// [`finish`]: HpkWriter::finish
+    pub(crate) fn with_header(mut w: W, header: Header) -> HpkResult<Self> {
+        header.write(&mut w)?;
+        Ok(HpkWriter {
+            w,
+            encoding: NameEncoding::default(),
+            fragments: vec![],
+            root: None,
+            dirs: HashMap::new(),
+            header: Some(header),
+        })
+    }
+
+    pub fn set_name_encoding(&mut self, encoding: NameEncoding) {
+        self.encoding = encoding;
+    }
+
+    /// Copies the data of the file unmodified into the archive
+    pub fn add_file<P, R>(&mut self, path: P, r: &mut R) -> HpkResult<u64>
+    where
+        P: AsRef<Path>,
+        R: Read + ?Sized,
+    {
+        self.add_file_with(path, |w| Ok(io::copy(r, w)?))
+    }
+
+    /// Adds a file with the data written by the closure, e.g. the output of [`compress`]
+    ///
+    /// [`compress`]: crate::compress()
+    pub fn add_file_with<P, F>(&mut self, path: P, op: F) -> HpkResult<u64>
+    where
+        P: AsRef<Path>,
+        F: FnOnce(&mut W) -> HpkResult<u64>,
+    {
+        let position = self.w.stream_position()?;
+        let n = op(&mut self.w)?;
+        if position + n > u64::from(u32::MAX) {
+            return Err(HpkError::TooLarge);
+        }
+
+        self.fragments.push(Fragment::new(position, n));
+        let dent = DirEntry::new_file(path.as_ref(), self.fragments.len() + 1, 0);
+        self.push_entry(&dent)?;
+        Ok(n)
+    }
+
+    /// Writes the listing of the directory. An empty path is the root directory.
+    pub fn add_dir<P: AsRef<Path>>(&mut self, path: P) -> HpkResult<()> {
+        let path = path.as_ref();
+        let listing = self.dirs.remove(path).unwrap_or_default();
+
+        let position = self.w.stream_position()?;
+        self.w.write_all(&listing)?;
+        let fragment = Fragment::new(position, listing.len() as u64);
+
+        if path.as_os_str().is_empty() {
+            // root dir must be the first fragment
+            self.root = Some(fragment);
+        } else {
+            self.fragments.push(fragment);
+            let dent = DirEntry::new_dir(path, self.fragments.len() + 1, 0);
+            self.push_entry(&dent)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the remaining directories, the fragment table and the header
+    pub fn finish(self) -> HpkResult<W> {
+        Ok(self.finish_with_header()?.0)
+    }
+
+    /// Finishes the archive and returns the written header
+    pub(crate) fn finish_with_header(mut self) -> HpkResult<(W, Header)> {
+        while let Some(path) = self
+            .dirs
+            .keys()
+            .filter(|p| !p.as_os_str().is_empty())
+            .max_by_key(|p| (p.components().count(), p.to_path_buf()))
+            .cloned()
+        {
+            self.add_dir(path)?;
+        }
+        if self.root.is_none() {
+            self.add_dir("")?;
+        }
+        if let Some(root) = self.root.take() {
+            self.fragments.insert(0, root);
+        }
+
+        let fragmented_filesystem_offset = self.w.stream_position()?;
+        let fragmented_filesystem_length = self.fragments.len() as u64 * 8;
+        for fragment in &self.fragments {
+            fragment.write(&mut self.w)?;
+        }
+
+        let header = Header::new(fragmented_filesystem_offset, fragmented_filesystem_length);
+        match self.header.take() {
+            Some(expected) => {
+                let matches = expected.fragmented_filesystem_offset == fragmented_filesystem_offset
+                    && expected.fragmented_filesystem_length == fragmented_filesystem_length;
+                if !matches {
+                    return Err(changed_input());
+                }
+            }
+            None => {
+                self.w.rewind()?;
+                header.write(&mut self.w)?;
+                self.w.seek(SeekFrom::End(0))?;
+            }
+        }
+
+        Ok((self.w, header))
+    }
+
+    fn push_entry(&mut self, dent: &DirEntry) -> HpkResult<()> {
+        let parent = dent.path().parent().unwrap_or(Path::new(""));
+        let listing = self.dirs.entry(parent.to_path_buf()).or_default();
+        dent.write(self.encoding, listing)
+    }
+}
+
+/// Writes a new archive with the files and directories in the order `hpk create`
+/// writes them, the `_filedates` file is the last entry of the root directory
+///
+/// The archive is written to a temp file next to the output file, `op` copies the
+/// data of a file into the archive.
+pub(crate) fn write_archive<'a, I, D, F>(
+    file: &Path,
+    encoding: NameEncoding,
+    files: I,
+    dirs: D,
+    filedates: Option<&[u8]>,
+    mut op: F,
+) -> HpkResult<()>
+where
+    I: IntoIterator<Item = &'a Path>,
+    D: IntoIterator<Item = &'a Path>,
+    F: FnMut(&mut HpkWriter<&mut File>, &Path) -> HpkResult<()>,
+{
+    let mut out = AtomicFile::new(file)?;
+    let mut writer = HpkWriter::new(out.as_file_mut())?;
+    writer.set_name_encoding(encoding);
+
+    for (path, is_dir) in contents_first(files, dirs) {
+        if !is_dir {
+            op(&mut writer, &path)?;
+            continue;
+        }
+        if path.as_os_str().is_empty() {
+            if let Some(mut filedates) = filedates {
+                writer.add_file("_filedates", &mut filedates)?;
+            }
+        }
+        writer.add_dir(&path)?;
+    }
+    writer.finish()?;
+    out.commit()
+}
+
+/// Returns the files and directories sorted by name with the contents of a directory
+/// followed by the directory itself like `WalkDir::contents_first` does
+///
+/// The missing parent directories are added and the root directory is the last entry.
+pub(crate) fn contents_first<'a, I, D>(files: I, dirs: D) -> Vec<(PathBuf, bool)>
+where
+    I: IntoIterator<Item = &'a Path>,
+    D: IntoIterator<Item = &'a Path>,
+{
+    let mut children: BTreeMap<&Path, BTreeMap<&OsStr, bool>> = BTreeMap::new();
+    let files = files.into_iter().map(|p| (p, false));
+    for (mut path, mut is_dir) in files.chain(dirs.into_iter().map(|p| (p, true))) {
+        while let (Some(parent), Some(name)) = (path.parent(), path.file_name()) {
+            children.entry(parent).or_default().insert(name, is_dir);
+            path = parent;
+            is_dir = true;
+        }
+    }
+
+    let mut order = vec![];
+    visit(Path::new(""), &children, &mut order);
+    return order;
+
+    fn visit(
+        dir: &Path,
+        children: &BTreeMap<&Path, BTreeMap<&OsStr, bool>>,
+        order: &mut Vec<(PathBuf, bool)>,
+    ) {
+        for (name, &is_dir) in children.get(dir).into_iter().flatten() {
+            let path = dir.join(name);
+            if is_dir {
+                visit(&path, children, order);
+            } else {
+                order.push((path, false));
+            }
+        }
+        order.push((dir.to_path_buf(), true));
+    }
+}
+
+/// Compresses the written data chunk by chunk like [`compress`]
+///
+/// The inflated length must be known up front to reserve the offset table, which is
+/// written by [`finish`] once the sizes of the compressed chunks are known. Only a
+/// single chunk is kept in memory.
+///
+/// [`compress`]: crate::compress()
+/// [`finish`]: ChunkWriter::finish
+pub(crate) struct ChunkWriter<W: Write + Seek> {
+    w: W,
+    options: CompressOptions,
+    inflated_length: u32,
+    /// Position of the compression header
+    start: u64,
+    header_size: u64,
+    chunk: Vec<u8>,
+    encoded: Vec<u8>,
+    offsets: Vec<u32>,
+    /// Table written up front by `with_table`
+    table: Option<ChunkTable>,
+    /// Size of the compressed chunks written so far
+    compressed: u64,
+    /// Uncompressed bytes written so far
+    position: u64,
+}
+
+/// Offset table and size of the data written by a [`ChunkWriter`]
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct ChunkTable {
+    pub(crate) offsets: Vec<u32>,
+    /// Size of the compression header and the compressed chunks
+    pub(crate) len: u64,
+}
+
+impl<W: Write + Seek> ChunkWriter<W> {
+    pub(crate) fn new(w: W, options: CompressOptions, inflated_length: u64) -> HpkResult<Self> {
+        let inflated_length = u32::try_from(inflated_length).map_err(|_| HpkError::TooLarge)?;
+        // the sum overflows 32 bits for lengths close to the limit
+        let chunk_size = u64::from(options.chunk_size.max(1));
+        let chunks = (u64::from(inflated_length) + chunk_size - 1) / chunk_size;
+        Self::init(w, options, inflated_length, vec![0; chunks as usize], None)
+    }
+
+    /// Writes the offset table of a previous run up front instead of rewriting it
+    ///
+    /// The writer doesn't need to seek back. [`finish`] fails if the compressed chunks
+    /// don't match the table.
+    ///
+    /// [`finish`]: ChunkWriter::finish
+    pub(crate) fn with_table(
+        w: W,
+        options: CompressOptions,
+        inflated_length: u64,
+        table: ChunkTable,
+    ) -> HpkResult<Self> {
+        let inflated_length = u32::try_from(inflated_length).map_err(|_| changed_input())?;
+        Self::init(
+            w,
+            options,
+            inflated_length,
+            table.offsets.clone(),
+            Some(table),
+        )
+    }
+
+    fn init(
+        mut w: W,
+        options: CompressOptions,
+        inflated_length: u32,
+        offsets: Vec<u32>,
+        table: Option<ChunkTable>,
+    ) -> HpkResult<Self> {
+        let start = w.stream_position()?;
+        let header_size = CompressionHeader::write(&options, inflated_length, &offsets, &mut w)?;
+
+        Ok(ChunkWriter {
+            w,
+            options,
+            inflated_length,
+            start,
+            header_size,
+            chunk: Vec::with_capacity(options.chunk_size.max(1) as usize),
+            encoded: vec![],
+            offsets: Vec::with_capacity(offsets.len()),
+            table,
+            compressed: 0,
+            position: 0,
+        })
+    }
+
+    fn write_chunk(&mut self) -> io::Result<()> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        let offset = u32::try_from(self.compressed).map_err(|_| too_large())?;
+        self.offsets.push(offset);
+
+        self.encoded.clear();
+        self.options.encode_chunk(&self.chunk, &mut self.encoded)?;
+        // the offsets of the chunks are relative to the compression header
+        let end = self.header_size + self.compressed + self.encoded.len() as u64;
+        if end > u64::from(u32::MAX) {
+            return Err(too_large());
+        }
+        self.w.write_all(&self.encoded)?;
+        self.compressed += self.encoded.len() as u64;
+        self.chunk.clear();
+        Ok(())
+    }
+
+    /// Writes the last chunk and the offset table of the chunks
+    pub(crate) fn finish(self) -> HpkResult<W> {
+        Ok(self.finish_with_table()?.0)
+    }
+
+    /// Finishes the compressed data and returns the written offset table
+    pub(crate) fn finish_with_table(mut self) -> HpkResult<(W, ChunkTable)> {
+        self.write_chunk()?;
+        if self.position != u64::from(self.inflated_length) {
+            return Err(changed_input());
+        }
+        let table = ChunkTable {
+            offsets: self.offsets,
+            len: self.header_size + self.compressed,
+        };
+        match self.table {
+            Some(expected) if expected != table => return Err(changed_input()),
+            Some(_) => {}
+            None => {
+                let end = self.w.stream_position()?;
+                self.w.seek(SeekFrom::Start(self.start))?;
+                CompressionHeader::write(
+                    &self.options,
+                    self.inflated_length,
+                    &table.offsets,
+                    &mut self.w,
+                )?;
+                self.w.seek(SeekFrom::Start(end))?;
+            }
+        }
+        Ok((self.w, table))
+    }
+}
+
+impl<W: Write + Seek> Write for ChunkWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk_size = self.options.chunk_size.max(1) as usize;
+        let n = buf.len().min(chunk_size - self.chunk.len());
+        if self.position + n as u64 > u64::from(self.inflated_length) {
+            return Err(changed_input_io());
+        }
+        self.chunk.extend_from_slice(&buf[..n]);
+        self.position += n as u64;
+        if self.chunk.len() == chunk_size {
+            self.write_chunk()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// Only reports the position, the compressed data can't be rewritten
+impl<W: Write + Seek> Seek for ChunkWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match pos {
+            SeekFrom::Current(0) => Ok(self.position),
+            SeekFrom::Start(pos) if pos == self.position => Ok(self.position),
+            _ => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "compressed archive can't be rewritten",
+            )),
+        }
+    }
+}
+
+/// Memory for the compressed files kept by the dry run of a compressed archive
+pub(crate) const KEPT_DATA_LIMIT: u64 = 64 << 20;
+
+/// Offset tables of the compressed files
+///
+/// The offset table of a compressed file is rewritten once the chunks are compressed.
+/// A compressed archive can't seek back, so the tables are recorded in the dry run
+/// and written up front while the archive is compressed.
+pub(crate) enum ChunkTables {
+    Rewrite,
+    /// The compressed files, `None` for files stored by the smart mode
+    Record {
+        files: VecDeque<Option<Compressed>>,
+        /// Size of the kept data
+        kept: u64,
+    },
+    Replay(VecDeque<Option<Compressed>>),
+}
+
+/// A file compressed by the dry run
+pub(crate) struct Compressed {
+    pub(crate) table: ChunkTable,
+    /// The compressed data until [`KEPT_DATA_LIMIT`] is reached, the other files
+    /// are compressed again
+    pub(crate) data: Option<Vec<u8>>,
+}
+
+impl ChunkTables {
+    pub(crate) fn record() -> Self {
+        ChunkTables::Record {
+            files: VecDeque::new(),
+            kept: 0,
+        }
+    }
+
+    pub(crate) fn replay(self) -> Self {
+        match self {
+            ChunkTables::Record { files, .. } => ChunkTables::Replay(files),
+            tables => tables,
+        }
+    }
+}
+
+/// Discards the written data and only keeps track of the size
+#[derive(Default)]
+pub(crate) struct SizeCounter {
+    position: u64,
+    len: u64,
+}
+
+impl Write for SizeCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.position += buf.len() as u64;
+        self.len = self.len.max(self.position);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SizeCounter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let position = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+        };
+        self.position = position.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )
+        })?;
+        Ok(self.position)
+    }
+}
+
+/// Marks the I/O error of data exceeding the 32-bit offsets, converted to [`HpkError::TooLarge`]
+#[derive(Debug)]
+pub(crate) struct TooLarge;
+
+impl fmt::Display for TooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("data exceeds the 4 GiB limit of the hpk format")
+    }
+}
+
+impl Error for TooLarge {}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, TooLarge)
+}
+
+fn changed_input_io() -> io::Error {
+    let msg = "the input changed while the archive was written";
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+pub(crate) fn changed_input() -> crate::HpkError {
+    changed_input_io().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn chunk_writer_matches_compress() {
+        let input = "Hello World, ".repeat(1000).into_bytes();
+        let options = CompressOptions {
+            chunk_size: 1024,
+            ..CompressOptions::default()
+        };
+
+        let mut expected = vec![];
+        crate::compress(&options, &mut &input[..], &mut expected).unwrap();
+
+        let mut w = ChunkWriter::new(Cursor::new(vec![]), options, input.len() as u64).unwrap();
+        for part in input.chunks(700) {
+            w.write_all(part).unwrap();
+        }
+        assert_eq!(w.stream_position().unwrap(), input.len() as u64);
+        let output = w.finish().unwrap().into_inner();
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn chunk_writer_rejects_changed_length() {
+        let options = CompressOptions::default();
+
+        let mut w = ChunkWriter::new(Cursor::new(vec![]), options, 10).unwrap();
+        assert!(w.write_all(&[0; 11]).is_err());
+
+        let mut w = ChunkWriter::new(Cursor::new(vec![]), options, 10).unwrap();
+        w.write_all(&[0; 9]).unwrap();
+        assert!(w.finish().is_err());
+    }
+
+    #[test]
+    fn chunk_writer_with_table() {
+        let input = "Hello World, ".repeat(1000).into_bytes();
+        let options = CompressOptions {
+            chunk_size: 1024,
+            ..CompressOptions::default()
+        };
+
+        let mut w = ChunkWriter::new(Cursor::new(vec![]), options, input.len() as u64).unwrap();
+        w.write_all(&input).unwrap();
+        let (expected, table) = w.finish_with_table().unwrap();
+        let expected = expected.into_inner();
+        assert_eq!(table.len, expected.len() as u64);
+
+        // the outer writer can't seek back
+        let outer = ChunkWriter::new(Cursor::new(vec![]), options, expected.len() as u64).unwrap();
+        let mut w = ChunkWriter::with_table(outer, options, input.len() as u64, table).unwrap();
+        w.write_all(&input).unwrap();
+        let (outer, table) = w.finish_with_table().unwrap();
+        outer.finish().unwrap();
+
+        let w = ChunkWriter::with_table(Cursor::new(vec![]), options, input.len() as u64, table);
+        let mut w = w.unwrap();
+        w.write_all(&input).unwrap();
+        assert_eq!(w.finish().unwrap().into_inner(), expected);
+
+        let table = ChunkTable {
+            offsets: vec![0; 13],
+            len: expected.len() as u64,
+        };
+        let w = ChunkWriter::with_table(Cursor::new(vec![]), options, input.len() as u64, table);
+        let mut w = w.unwrap();
+        w.write_all(&input).unwrap();
+        assert!(w.finish().is_err());
+    }
+
+    #[test]
+    fn reject_data_beyond_32bit_offsets() {
+        let mut writer = HpkWriter::new(SizeCounter::default()).unwrap();
+        let err = writer.add_file_with("large.bin", |w| {
+            // skips 4 GiB without writing them
+            w.seek(SeekFrom::Current(i64::from(u32::MAX)))?;
+            Ok(u64::from(u32::MAX))
+        });
+        assert!(std::matches!(err, Err(HpkError::TooLarge)));
+
+        let fragment = Fragment::new(u64::from(u32::MAX), 1);
+        let err = fragment.write(&mut io::sink());
+        assert!(std::matches!(err, Err(HpkError::TooLarge)));
+
+        let options = CompressOptions::default();
+        let w = ChunkWriter::new(Cursor::new(vec![]), options, 1 << 32);
+        assert!(std::matches!(w, Err(HpkError::TooLarge)));
+
+        // the table of the largest data has a slot for every chunk
+        for chunk_size in [1 << 15, u32::MAX - 1] {
+            let options = CompressOptions {
+                chunk_size,
+                ..CompressOptions::default()
+            };
+            let len = u64::from(u32::MAX);
+            let w = ChunkWriter::new(SizeCounter::default(), options, len).unwrap();
+            let chunks = (len + u64::from(chunk_size) - 1) / u64::from(chunk_size);
+            assert_eq!(w.header_size, 12 + 4 * chunks);
+        }
+    }
+}
@@ -31,14 +31,34 @@ fn main() -> CliResult {
         .arg_required_else_help(true)
         .subcommand(commands::create::cmd())
         .subcommand(commands::extract::cmd())
+        .subcommand(commands::compress::cmd())
+        .subcommand(commands::decompress::cmd())
+        .subcommand(commands::merge::cmd())
+        .subcommand(commands::split::cmd())
+        .subcommand(commands::copy_members::cmd())
+        .subcommand(commands::recompress::cmd())
         .subcommand(commands::list::cmd())
+        .subcommand(commands::overlay::cmd())
+        .subcommand(commands::info::cmd())
+        .subcommand(commands::lua_info::cmd())
+        .subcommand(commands::lua::cmd())
         .subcommand(commands::print::cmd())
         .get_matches();
 
     match matches.subcommand() {
         Some(("create", matches)) => commands::create::execute(matches)?,
         Some(("extract", matches)) => commands::extract::execute(matches)?,
+        Some(("compress", matches)) => commands::compress::execute(matches)?,
+        Some(("decompress", matches)) => commands::decompress::execute(matches)?,
+        Some(("merge", matches)) => commands::merge::execute(matches)?,
+        Some(("split", matches)) => commands::split::execute(matches)?,
+        Some(("copy-members", matches)) => commands::copy_members::execute(matches)?,
+        Some(("recompress", matches)) => commands::recompress::execute(matches)?,
         Some(("list", matches)) => commands::list::execute(matches)?,
+        Some(("overlay", matches)) => commands::overlay::execute(matches)?,
+        Some(("info", matches)) => commands::info::execute(matches)?,
+        Some(("lua-info", matches)) => commands::lua_info::execute(matches)?,
+        Some(("lua", matches)) => commands::lua::execute(matches)?,
         Some(("debug-print", matches)) => commands::print::execute(matches)?,
         _ => unreachable!(),
     };
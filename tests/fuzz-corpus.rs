@@ -0,0 +1,52 @@
+//! Replays the regression corpus of the fuzz targets in `fuzz/`
+#![cfg(feature = "fuzzing")]
+
+use std::fs;
+use std::path::Path;
+
+use hpk::compress::{Lz4Block, Zlib, Zstd};
+
+fn replay(target: &str, f: fn(&[u8])) {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("fuzz/corpus")
+        .join(target);
+    let mut inputs = 0;
+    for entry in fs::read_dir(&dir).unwrap() {
+        let data = fs::read(entry.unwrap().path()).unwrap();
+        f(&data);
+        inputs += 1;
+    }
+    assert!(inputs > 0, "empty corpus: {}", dir.display());
+}
+
+#[test]
+fn header() {
+    replay("header", hpk::fuzzing::header);
+}
+
+#[test]
+fn dir_entry() {
+    replay("dir_entry", hpk::fuzzing::dir_entries);
+}
+
+#[test]
+fn compression_header() {
+    replay("compression_header", hpk::fuzzing::compression_header);
+}
+
+#[test]
+fn walk() {
+    replay("walk", hpk::fuzzing::walk);
+}
+
+#[test]
+fn decoders() {
+    replay("decode_zlib", hpk::fuzzing::decode::<Zlib>);
+    replay("decode_lz4", hpk::fuzzing::decode::<Lz4Block>);
+    replay("decode_zstd", hpk::fuzzing::decode::<Zstd>);
+}
+
+#[test]
+fn lua() {
+    replay("lua", hpk::fuzzing::lua);
+}
@@ -2,7 +2,8 @@ use std::env;
 use std::fs;
 use std::io;
 use std::io::prelude::*;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
 
 macro_rules! assert_path_exists {
     ($p:expr) => {
@@ -10,6 +11,62 @@ macro_rules! assert_path_exists {
     };
 }
 
+fn temp_root() -> tempfile::TempDir {
+    let root = tempfile::Builder::new().prefix("hpk-tests").tempdir();
+    root.expect("Should have created a temp directory")
+}
+
+/// Writes the files into the folder `name` of the root and returns the folder
+fn write_files<C: AsRef<[u8]>>(root: &Path, name: &str, files: &[(&str, C)]) -> PathBuf {
+    let dir = root.join(name);
+    for (path, content) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+    dir
+}
+
+/// Creates the archive `name.hpk` from the files written into the folder `name`
+fn create_archive<C: AsRef<[u8]>>(
+    root: &Path,
+    name: &str,
+    files: &[(&str, C)],
+    options: &hpk::CreateOptions,
+) -> PathBuf {
+    let dir = write_files(root, name, files);
+    let file = root.join(name).with_extension("hpk");
+    hpk::create(options, &dir, &file).unwrap();
+    file
+}
+
+/// Maps the stored data of every file of the archive
+fn map_members<T, F>(file: &Path, mut f: F) -> Vec<(String, T)>
+where
+    F: FnMut(&mut hpk::FragmentedReader<&fs::File>) -> Result<T, hpk::HpkError>,
+{
+    let mut walk = hpk::walk(file).unwrap();
+    let entries: Vec<_> = walk.by_ref().map(Result::unwrap).collect();
+    let mut members = vec![];
+    for entry in entries.iter().filter(|e| !e.is_dir()) {
+        walk.read_file(entry, |mut r| {
+            members.push((entry.path().display().to_string(), f(&mut r)?));
+            Ok(())
+        })
+        .unwrap();
+    }
+    members
+}
+
+/// Returns the stored data of every file of the archive
+fn stored_members(file: &Path) -> Vec<(String, Vec<u8>)> {
+    map_members(file, |r| {
+        let mut buf = vec![];
+        r.read_to_end(&mut buf)?;
+        Ok(buf)
+    })
+}
+
 #[test]
 fn create_extract_and_compress() {
     fn create_dir(path: &str) {
@@ -23,8 +80,7 @@ fn create_extract_and_compress() {
         }
     }
 
-    let root = tempfile::Builder::new().prefix("hpk-tests").tempdir();
-    let root = root.expect("Should have created a temp director");
+    let root = temp_root();
     assert!(env::set_current_dir(root.path()).is_ok());
 
     create_dir("test1");
@@ -63,9 +119,9 @@ fn create_extract_and_compress() {
     assert_path_exists!("test1-extracted/folder/six_bytes");
     assert_path_exists!("test1-extracted/two_bytes");
 
-    let _ = fs::read("lua-extracted/script32.lua")
+    let _ = fs::read("test1-extracted/script32.lua")
         .map(|c| assert_eq!(c, &include_bytes!("valid32.lua")[..]));
-    let _ = fs::read("lua-extracted/script64.lua")
+    let _ = fs::read("test1-extracted/script64.lua")
         .map(|c| assert_eq!(c, &include_bytes!("valid64.lua")[..]));
 
     let mut walk = hpk::walk("test1.hpk").unwrap();
@@ -101,3 +157,1180 @@ fn create_extract_and_compress() {
         }
     }
 }
+
+#[test]
+fn create_with_game_profile() {
+    let root = temp_root();
+    let files = [
+        ("script32.lua", &include_bytes!("valid32.lua")[..]),
+        ("script64.lua", &include_bytes!("valid64.lua")[..]),
+        ("data.xml", "<xml></xml>".as_bytes()),
+    ];
+    let input = write_files(root.path(), "game", &files);
+
+    let read_members = |file: &Path| {
+        map_members(file, |r| {
+            let compression = hpk::get_compression(r)?;
+            let mut buf = vec![];
+            hpk::copy(r, &mut buf)?;
+            Ok((compression, buf))
+        })
+    };
+
+    let file = root.path().join("surviving-mars.hpk");
+    let mut options = hpk::CreateOptions::new();
+    options.with_game(hpk::Game::SurvivingMars);
+    hpk::create(&options, &input, &file).unwrap();
+
+    let members = read_members(&file);
+    assert_eq!(members.len(), 3);
+    for (path, (compression, data)) in members {
+        assert!(compression == hpk::Compression::Lz4, "{}", path);
+        if path == "script32.lua" {
+            assert_eq!(data, &include_bytes!("broken32.lua")[..]);
+        }
+        if path == "script64.lua" {
+            assert_eq!(data, &include_bytes!("broken64.lua")[..]);
+        }
+    }
+
+    let file = root.path().join("surviving-mars-dlc.hpk");
+    let mut options = hpk::CreateOptions::new();
+    options.with_game(hpk::Game::SurvivingMarsDlc);
+    hpk::create(&options, &input, &file).unwrap();
+
+    for (path, (compression, _)) in read_members(&file) {
+        assert!(!compression.is_compressed(), "{}", path);
+    }
+
+    let file = root.path().join("tropico4.hpk");
+    let mut options = hpk::CreateOptions::new();
+    options.with_game(hpk::Game::Tropico4);
+    hpk::create(&options, &input, &file).unwrap();
+
+    let members = read_members(&file);
+    assert_eq!(members.len(), 4);
+    assert!(members.iter().any(|m| m.0 == "_filedates"));
+}
+
+#[test]
+fn detect_archive_settings() {
+    let root = temp_root();
+    let files = [
+        ("script.lua", &include_bytes!("valid32.lua")[..]),
+        ("data.xml", "<xml></xml>".as_bytes()),
+    ];
+    let input = write_files(root.path(), "info", &files);
+    let lua51 = b"\x1BLua\x51\x00\x01\x04\x04\x04\x08\x00";
+    let files = [
+        ("script.lua", &lua51[..]),
+        ("data.xml", "<xml></xml>".as_bytes()),
+    ];
+    let tropico = write_files(root.path(), "tropico", &files);
+
+    let file = root.path().join("tropico4.hpk");
+    let mut options = hpk::CreateOptions::new();
+    options.with_game(hpk::Game::Tropico4);
+    hpk::create(&options, &tropico, &file).unwrap();
+
+    let info = hpk::info(&file).unwrap();
+    assert_eq!(info.files, 2);
+    assert_eq!(info.zlib_files, 2);
+    assert_eq!(info.filedates, Some(hpk::FileDateFormat::Short));
+    assert_eq!(info.lua.lua51, 1);
+    assert_eq!(info.games(), [hpk::Game::Tropico4, hpk::Game::Omerta]);
+
+    // Lua 5.3 bytecode rules out the games with Lua 5.1 scripts
+    hpk::create(&options, &input, &file).unwrap();
+    assert_eq!(hpk::info(&file).unwrap().games(), []);
+
+    let file = root.path().join("tropico3.hpk");
+    let mut options = hpk::CreateOptions::new();
+    options.with_game(hpk::Game::Tropico3);
+    hpk::create(&options, &tropico, &file).unwrap();
+    assert_eq!(hpk::info(&file).unwrap().games(), [hpk::Game::Tropico3]);
+
+    let file = root.path().join("victor-vran.hpk");
+    let mut options = hpk::CreateOptions::new();
+    options.with_game(hpk::Game::VictorVran);
+    options.compress();
+    hpk::create(&options, &input, &file).unwrap();
+
+    let info = hpk::info(&file).unwrap();
+    assert_eq!(info.compression, hpk::Compression::Lz4);
+    assert_eq!(info.lz4_files, 2);
+    assert_eq!(info.filedates, None);
+    assert_eq!(info.lua.crippled, 1);
+    assert_eq!(info.games(), [hpk::Game::VictorVran]);
+
+    // a default extension that is not compressed in the archive stays uncompressed
+    fs::write(input.join("texture.dds"), "DDS ").unwrap();
+    let like = root.path().join("like.hpk");
+    let mut options = hpk::CreateOptions::new();
+    options.like(&info);
+    hpk::create(&options, &input, &like).unwrap();
+
+    let like = hpk::info(&like).unwrap();
+    assert_eq!(like.compression, hpk::Compression::Lz4);
+    assert_eq!(like.files, 3);
+    assert_eq!(like.lz4_files, 2);
+    assert_eq!(like.lua.crippled, 1);
+
+    // the encoder of the whole archive differs from the one of the files
+    let plain = root.path().join("plain.hpk");
+    let mut options = hpk::CreateOptions::new();
+    options.use_lz4();
+    hpk::create(&options, &input, &plain).unwrap();
+    let whole = root.path().join("whole.hpk");
+    let mut options = hpk::CompressOptions::default();
+    options.use_zstd();
+    hpk::compress_file(&options, &plain, &whole).unwrap();
+    let info = hpk::info(&whole).unwrap();
+    assert_eq!(info.compressor(), hpk::Compression::Lz4);
+
+    let mut options = hpk::CreateOptions::new();
+    options.like(&info);
+    hpk::create(&options, &input, &file).unwrap();
+    assert_eq!(
+        hpk::info(&file).unwrap().compression,
+        hpk::Compression::Zstd
+    );
+}
+
+#[test]
+fn inspect_lua_members() {
+    let root = temp_root();
+    let files = [
+        ("valid.lua", &include_bytes!("valid64.lua")[..]),
+        ("broken.lua", &include_bytes!("broken32.lua")[..]),
+        ("source.lua", "print('Hello World')".as_bytes()),
+        ("unknown.lua", &b"\x1BLua\x54\x00\x19\x93"[..]),
+    ];
+
+    // the header spans several chunks of the compressed files
+    let mut options = hpk::CreateOptions::new();
+    options.with_chunk_size(16);
+    let file = create_archive(root.path(), "lua", &files, &options);
+
+    let mut scripts = map_members(&file, |r| {
+        let buf = hpk::read_head(r, hpk::lua::MAX_HEADER_LEN)?;
+        Ok(hpk::inspect_lua(&buf))
+    });
+    scripts.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let valid = hpk::LuaHeader {
+        version: hpk::LuaVersion::Lua53,
+        little_endian: true,
+        sizeof_int: 4,
+        sizeof_size_t: 4,
+        sizeof_instruction: 4,
+        sizeof_integer: Some(8),
+        sizeof_number: 8,
+        integral: false,
+        crippled: false,
+    };
+    let broken = hpk::LuaHeader {
+        sizeof_integer: Some(4),
+        crippled: true,
+        ..valid.clone()
+    };
+    let expected = [
+        ("broken.lua", hpk::LuaScript::Bytecode(broken)),
+        ("source.lua", hpk::LuaScript::Source),
+        ("unknown.lua", hpk::LuaScript::Unknown),
+        ("valid.lua", hpk::LuaScript::Bytecode(valid)),
+    ];
+    assert_eq!(scripts.len(), expected.len());
+    for ((path, script), (name, expected)) in scripts.iter().zip(expected) {
+        assert_eq!(path, name);
+        assert_eq!(script, &expected, "{}", name);
+    }
+}
+
+#[test]
+fn lua_fix_and_cripple_files() {
+    let lua = |args: &[&str], files: &[&Path]| {
+        process::Command::new(env!("CARGO_BIN_EXE_hpk"))
+            .arg("lua")
+            .args(args)
+            .args(files)
+            .output()
+            .unwrap()
+    };
+    let root = temp_root();
+    let broken32 = &include_bytes!("broken32.lua")[..];
+    let broken64 = &include_bytes!("broken64.lua")[..];
+    let files = [("script32.lua", broken32), ("script64.lua", broken64)];
+    let a = write_files(root.path(), "a", &files);
+    let script32 = a.join("script32.lua");
+    let script64 = a.join("script64.lua");
+
+    // fix into an output directory, the input files stay untouched
+    let fixed = root.path().join("fixed");
+    let out = lua(&["fix", "-o"], &[&fixed, &script32, &script64]);
+    assert!(out.status.success());
+    let valid32 = fs::read(fixed.join("script32.lua")).unwrap();
+    let valid64 = fs::read(fixed.join("script64.lua")).unwrap();
+    assert_eq!(valid32, &include_bytes!("valid32.lua")[..]);
+    assert_eq!(valid64, &include_bytes!("valid64.lua")[..]);
+    assert_eq!(fs::read(&script32).unwrap(), broken32);
+
+    // cripple the fixed files in place
+    let fixed32 = fixed.join("script32.lua");
+    let fixed64 = fixed.join("script64.lua");
+    let out = lua(&["cripple", "-i"], &[&fixed32, &fixed64]);
+    assert!(out.status.success());
+    assert_eq!(fs::read(&fixed32).unwrap(), broken32);
+    assert_eq!(fs::read(&fixed64).unwrap(), broken64);
+
+    // fix in place and round trip
+    let out = lua(&["fix", "-i"], &[&fixed32]);
+    assert!(out.status.success());
+    assert_eq!(fs::read(&fixed32).unwrap(), valid32);
+
+    // files with the same name can't be written to one output directory
+    let b = write_files(root.path(), "b", &[("script32.lua", broken32)]);
+    let other = b.join("script32.lua");
+    let dup = root.path().join("dup");
+    let out = lua(&["fix", "-o"], &[&dup, &script32, &other]);
+    assert!(!out.status.success());
+    assert!(!dup.exists());
+}
+
+#[test]
+fn extract_to_tar_stream() {
+    let root = temp_root();
+    let xml = "<xml>Hello World</xml>\n".repeat(100);
+    let files = [
+        ("script.lua", &include_bytes!("broken64.lua")[..]),
+        ("valid.lua", &include_bytes!("valid32.lua")[..]),
+        ("folder/six_bytes", "ABCDEF".as_bytes()),
+        ("folder/data.xml", xml.as_bytes()),
+    ];
+
+    // the compressed files are streamed from several chunks
+    let mut options = hpk::CreateOptions::new();
+    options.with_default_filedates_format();
+    options.with_chunk_size(256);
+    let file = create_archive(root.path(), "tar", &files, &options);
+
+    let mut options = hpk::ExtractOptions::new();
+    options.fix_lua_files();
+    let buf = hpk::extract_to_tar(&options, &file, vec![]).unwrap();
+
+    let mut archive = tar::Archive::new(&buf[..]);
+    let mut entries = vec![];
+    for entry in archive.entries().unwrap() {
+        let mut entry = entry.unwrap();
+        let path = entry.path().unwrap().into_owned();
+        let mtime = entry.header().mtime().unwrap();
+        let mut data = vec![];
+        io::copy(&mut entry, &mut data).unwrap();
+        entries.push((path, mtime, data));
+    }
+
+    let paths: Vec<_> = entries.iter().map(|e| e.0.to_str().unwrap()).collect();
+    assert_eq!(
+        paths,
+        [
+            "folder",
+            "folder/data.xml",
+            "folder/six_bytes",
+            "script.lua",
+            "valid.lua"
+        ]
+    );
+    assert!(entries.iter().all(|e| e.1 > 0));
+    assert_eq!(entries[1].2, xml.as_bytes());
+    assert_eq!(entries[2].2, b"ABCDEF");
+    assert_eq!(entries[3].2, &include_bytes!("valid64.lua")[..]);
+    assert_eq!(entries[4].2, &include_bytes!("valid32.lua")[..]);
+}
+
+#[test]
+fn reject_unsafe_member_names() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let root = temp_root();
+    let files = [("folder/XXXX", "escaped"), ("folder/six_bytes", "ABCDEF")];
+    let file = create_archive(root.path(), "unsafe", &files, &hpk::CreateOptions::new());
+
+    // Rename the member `XXXX` to `../x`
+    let mut buf = fs::read(&file).unwrap();
+    let name = b"\x04\x00XXXX";
+    let pos = buf.windows(name.len()).position(|w| w == name).unwrap();
+    buf[pos + 2..pos + 6].copy_from_slice(b"../x");
+    fs::write(&file, buf).unwrap();
+
+    let dest = root.path().join("dest/out");
+    let options = hpk::ExtractOptions::new();
+    match hpk::extract(&options, &file, &dest) {
+        Err(hpk::HpkError::UnsafeDirEntryName(path)) => {
+            assert_eq!(path, Path::new("folder/../x"));
+        }
+        _ => panic!("unsafe member name not rejected"),
+    }
+
+    struct Skipped(Rc<RefCell<Vec<PathBuf>>>);
+
+    impl hpk::Hooks for Skipped {
+        fn error(&self, error: &hpk::HpkError) {
+            if let hpk::HpkError::UnsafeDirEntryName(path) = error {
+                self.0.borrow_mut().push(path.clone());
+            }
+        }
+    }
+
+    let skipped = Rc::new(RefCell::new(vec![]));
+    let mut options = hpk::ExtractOptions::new();
+    options.skip_unsafe_paths();
+    options.set_hooks(Skipped(Rc::clone(&skipped)));
+    hpk::extract(&options, &file, &dest).unwrap();
+    assert_eq!(*skipped.borrow(), [Path::new("folder/../x")]);
+    assert!(dest.join("folder/six_bytes").exists());
+    assert!(!dest.join("x").exists());
+    assert!(!dest.join("folder/x").exists());
+}
+
+#[test]
+fn reject_malformed_metadata() {
+    let root = temp_root();
+    let files = [("folder/subfolder/six_bytes", "ABCDEF")];
+    let file = create_archive(root.path(), "malformed", &files, &hpk::CreateOptions::new());
+    let orig = fs::read(&file).unwrap();
+
+    // Point `subfolder` back to the fragment of `folder`
+    let mut buf = orig.clone();
+    let find = |buf: &[u8], name: &[u8]| buf.windows(name.len()).position(|w| w == name).unwrap();
+    let folder = find(&buf, b"\x06\x00folder") - 8;
+    let subfolder = find(&buf, b"\x09\x00subfolder") - 8;
+    let index = buf[folder..folder + 4].to_vec();
+    buf[subfolder..subfolder + 4].copy_from_slice(&index);
+    fs::write(&file, &buf).unwrap();
+
+    let entries: Vec<_> = hpk::walk(&file).unwrap().collect();
+    assert_eq!(entries.len(), 3);
+    match &entries[2] {
+        Err(hpk::HpkError::CyclicDirEntry(path)) => {
+            assert_eq!(path, Path::new("folder/subfolder"));
+        }
+        _ => panic!("cycle not detected"),
+    }
+
+    // Point `other` to the entries of `folder`
+    let files = [("folder/six_bytes", "ABCDEF"), ("other/two_bytes", "AB")];
+    let shared = create_archive(root.path(), "shared", &files, &hpk::CreateOptions::new());
+    let mut buf = fs::read(&shared).unwrap();
+    let folder = find(&buf, b"\x06\x00folder") - 8;
+    let other = find(&buf, b"\x05\x00other") - 8;
+    let index = buf[folder..folder + 4].to_vec();
+    buf[other..other + 4].copy_from_slice(&index);
+    fs::write(&shared, &buf).unwrap();
+
+    let entries: Vec<_> = hpk::walk(&shared).unwrap().collect();
+    assert_eq!(entries.len(), 4);
+    match &entries[3] {
+        Err(hpk::HpkError::DuplicateDirEntry(path)) => {
+            assert_eq!(path, Path::new("other"));
+        }
+        _ => panic!("shared directory not detected"),
+    }
+
+    // Filesystem fragments beyond the end of the file
+    let mut buf = orig.clone();
+    buf[32..36].copy_from_slice(&u32::MAX.to_le_bytes());
+    fs::write(&file, &buf).unwrap();
+    assert!(std::matches!(
+        hpk::walk(&file),
+        Err(hpk::HpkError::InvalidHeader)
+    ));
+
+    // Offset table larger than the data and decreasing chunk offsets
+    for offsets in [&[0xFFFF_u32][..], &[8], &[24, 20]] {
+        let mut buf = b"ZLIB".to_vec();
+        buf.extend_from_slice(&[0; 8]);
+        for offset in offsets {
+            buf.extend_from_slice(&offset.to_le_bytes());
+        }
+        buf.extend_from_slice(&[0; 8]);
+        let len = buf.len() as u64;
+        let hdr = hpk::CompressionHeader::read_from(len, &mut &buf[..]);
+        assert!(std::matches!(
+            hdr,
+            Err(hpk::HpkError::InvalidCompressionHeader)
+        ));
+    }
+}
+
+#[test]
+fn legacy_name_encoding() {
+    let root = temp_root();
+    let files = [("données/café.txt", "ABCDEF"), ("two_bytes", "AB")];
+    let mut options = hpk::CreateOptions::new();
+    options.with_default_filedates_format();
+    options.set_name_encoding(hpk::NameEncoding::Cp1252);
+    let file = create_archive(root.path(), "encoding", &files, &options);
+
+    let buf = fs::read(&file).unwrap();
+    assert!(buf.windows(8).any(|w| w == b"caf\xe9.txt"));
+
+    let options = hpk::ExtractOptions::new();
+    let dest = root.path().join("utf8");
+    assert!(std::matches!(
+        hpk::extract(&options, &file, &dest),
+        Err(hpk::HpkError::InvalidDirEntryName)
+    ));
+
+    let mut walk = hpk::walk(&file).unwrap();
+    walk.set_name_encoding(hpk::NameEncoding::Lossy);
+    let paths: Vec<_> = walk.flatten().map(|e| e.path().to_path_buf()).collect();
+    assert!(paths.contains(&Path::new("donn\u{FFFD}es/caf\u{FFFD}.txt").to_path_buf()));
+
+    let mut options = hpk::ExtractOptions::new();
+    options.set_name_encoding(hpk::NameEncoding::Cp1252);
+    let dest = root.path().join("cp1252");
+    hpk::extract(&options, &file, &dest).unwrap();
+    let extracted = dest.join("données/café.txt");
+    assert_eq!(fs::read(&extracted).unwrap(), b"ABCDEF");
+    let mtime = |path: &Path| {
+        let mtime = fs::metadata(path).unwrap().modified().unwrap();
+        mtime
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    };
+    assert_eq!(
+        mtime(&extracted),
+        mtime(&root.path().join("encoding/données/café.txt"))
+    );
+}
+
+#[test]
+fn repack_from_manifest() {
+    let root = temp_root();
+    let files = [
+        ("script.lua", &include_bytes!("broken64.lua")[..]),
+        ("folder/data.xml", "<data/>".as_bytes()),
+        ("folder/six_bytes", "ABCDEF".as_bytes()),
+    ];
+    let input = write_files(root.path(), "manifest", &files);
+    fs::create_dir(input.join("empty_folder")).unwrap();
+
+    let file = root.path().join("manifest.hpk");
+    let mut options = hpk::CreateOptions::new();
+    options.with_game(hpk::Game::VictorVran);
+    options.with_short_filedates_format();
+    options.with_chunk_size(4096);
+    hpk::create(&options, &input, &file).unwrap();
+
+    let dest = root.path().join("extracted");
+    let mut options = hpk::ExtractOptions::new();
+    options.fix_lua_files();
+    options.write_manifest();
+    hpk::extract(&options, &file, &dest).unwrap();
+    assert_eq!(
+        fs::read(dest.join("script.lua")).unwrap(),
+        include_bytes!("valid64.lua")
+    );
+
+    let manifest = hpk::Manifest::read_from(dest.join(hpk::MANIFEST_NAME)).unwrap();
+    assert!(manifest.filedates.is_some());
+    let repacked = root.path().join("repacked.hpk");
+    let mut options = hpk::CreateOptions::new();
+    options.with_manifest(manifest);
+    hpk::create(&options, &dest, &repacked).unwrap();
+    assert_eq!(fs::read(&file).unwrap(), fs::read(&repacked).unwrap());
+
+    // The order of the manifest entries is kept
+    let mut manifest = hpk::Manifest::read_from(dest.join(hpk::MANIFEST_NAME)).unwrap();
+    manifest.entries.reverse();
+    let mut options = hpk::CreateOptions::new();
+    options.with_manifest(manifest);
+    hpk::create(&options, &dest, &repacked).unwrap();
+
+    let paths: Vec<_> = hpk::walk(&repacked)
+        .unwrap()
+        .flatten()
+        .filter(|e| e.depth() == 1)
+        .map(|e| e.path().to_string_lossy().into_owned())
+        .collect();
+    assert_eq!(
+        paths,
+        ["_filedates", "script.lua", "folder", "empty_folder"]
+    );
+}
+
+#[test]
+fn smart_compression_rules() {
+    let root = temp_root();
+    // incompressible data of an already compressed texture
+    let mut seed = 0x2545_f491_u32;
+    let noise: Vec<u8> = (0..16384)
+        .map(|_| {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        })
+        .collect();
+    let files = [
+        ("texture.dds", noise.clone()),
+        ("readme.txt", "Hello World\n".repeat(100).into_bytes()),
+        ("sounds/theme.ogg", "la".repeat(1000).into_bytes()),
+        ("tiny.xml", b"<a/>".to_vec()),
+    ];
+    let input = write_files(root.path(), "smart", &files);
+
+    let compressed_members = |file: &Path| {
+        let members = map_members(file, |r| hpk::get_compression(r));
+        members
+            .into_iter()
+            .filter(|(_, compression)| compression.is_compressed())
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>()
+    };
+
+    let file = root.path().join("smart.hpk");
+    let mut options = hpk::CreateOptions::new();
+    options.with_min_savings(10);
+    options.set_exclude(&[glob::Pattern::new("sounds/*").unwrap()]);
+    options.with_min_compress_size(16);
+    hpk::create(&options, &input, &file).unwrap();
+    assert_eq!(compressed_members(&file), ["readme.txt"]);
+
+    let file = root.path().join("rules.hpk");
+    let mut options = hpk::CreateOptions::new();
+    options.set_include(&[glob::Pattern::new("*.ogg").unwrap()]);
+    options.with_max_compress_size(4096);
+    hpk::create(&options, &input, &file).unwrap();
+    assert_eq!(compressed_members(&file), ["sounds/theme.ogg", "tiny.xml"]);
+
+    let extracted = root.path().join("extracted");
+    hpk::extract(&hpk::ExtractOptions::new(), &file, &extracted).unwrap();
+    assert_eq!(fs::read(extracted.join("texture.dds")).unwrap(), noise);
+}
+
+#[test]
+fn report_progress() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let root = temp_root();
+    let files = [
+        ("data.xml", "<xml></xml>".repeat(50)),
+        ("folder/six_bytes", String::from("ABCDEF")),
+    ];
+    let input = write_files(root.path(), "progress", &files);
+
+    let record = |events: &Rc<RefCell<Vec<_>>>| {
+        let events = Rc::clone(events);
+        move |p: &hpk::Progress<'_>| {
+            let entry = p
+                .entry
+                .map(|e| (e.path.to_path_buf(), e.inflated, e.stored));
+            let counts = (p.files, p.total_files, p.bytes, p.total_bytes);
+            events.borrow_mut().push((p.stage, entry, counts));
+        }
+    };
+
+    let file = root.path().join("progress.hpk");
+    let events = Rc::new(RefCell::new(vec![]));
+    let mut options = hpk::CreateOptions::new();
+    options.compress();
+    options.set_progress(record(&events));
+    hpk::create(&options, &input, &file).unwrap();
+
+    let events = events.take();
+    let (files, measure): (Vec<_>, Vec<_>) = events
+        .iter()
+        .partition(|e| e.0 == hpk::ProgressStage::Entries);
+    assert_eq!(files.len(), 2);
+    assert_eq!(measure.len(), 2);
+    let (_, entry, counts) = &files[0];
+    let (path, inflated, stored) = entry.as_ref().unwrap();
+    assert_eq!(path, Path::new("data.xml"));
+    assert_eq!(*inflated, 550);
+    assert!(stored < inflated);
+    assert_eq!(*counts, (1, 2, 550, 556));
+    assert_eq!(files[1].2, (2, 2, 556, 556));
+    assert_eq!(measure[1].2, (2, 2, 556, 556));
+
+    let events = Rc::new(RefCell::new(vec![]));
+    let mut options = hpk::ExtractOptions::new();
+    options.set_paths(&[glob::Pattern::new("folder/*").unwrap()]);
+    options.set_progress(record(&events));
+    hpk::extract(&options, &file, &root.path().join("extracted")).unwrap();
+
+    let events = events.take();
+    assert_eq!(events.len(), 1);
+    let (_, entry, counts) = &events[0];
+    assert_eq!(entry, &Some((PathBuf::from("folder/six_bytes"), 6, 6)));
+    assert_eq!(*counts, (1, 1, 6, 6));
+}
+
+#[test]
+fn cancel_with_hooks() {
+    use std::cell::RefCell;
+    use std::ops::ControlFlow;
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct Events {
+        started: Vec<PathBuf>,
+        done: usize,
+        errors: usize,
+    }
+
+    struct Cancel {
+        events: Rc<RefCell<Events>>,
+        after_bytes: Option<u64>,
+        after_files: Option<usize>,
+    }
+
+    impl hpk::Hooks for Cancel {
+        fn entry_start(&self, path: &Path) -> ControlFlow<()> {
+            self.events.borrow_mut().started.push(path.to_path_buf());
+            ControlFlow::Continue(())
+        }
+
+        fn bytes(&self, _path: &Path, written: u64) -> ControlFlow<()> {
+            match self.after_bytes {
+                Some(max) if written > max => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            }
+        }
+
+        fn entry_done(&self, _progress: &hpk::Progress<'_>) -> ControlFlow<()> {
+            let mut events = self.events.borrow_mut();
+            events.done += 1;
+            match self.after_files {
+                Some(max) if events.done >= max => ControlFlow::Break(()),
+                _ => ControlFlow::Continue(()),
+            }
+        }
+
+        fn error(&self, _error: &hpk::HpkError) {
+            self.events.borrow_mut().errors += 1;
+        }
+    }
+
+    let root = temp_root();
+    let files = [
+        ("a.xml", b"<xml></xml>".to_vec()),
+        ("b.bin", vec![7; 100_000]),
+    ];
+    let input = write_files(root.path(), "hooks", &files);
+
+    // cancelled after the first file
+    let file = root.path().join("cancelled.hpk");
+    let events = Rc::new(RefCell::new(Events::default()));
+    let mut options = hpk::CreateOptions::new();
+    options.set_hooks(Cancel {
+        events: Rc::clone(&events),
+        after_bytes: None,
+        after_files: Some(1),
+    });
+    let err = hpk::create(&options, &input, &file).unwrap_err();
+    assert!(std::matches!(err, hpk::HpkError::Cancelled));
+    assert!(!file.exists());
+    assert_eq!(events.borrow().started, [Path::new("a.xml")]);
+    assert_eq!(events.borrow().errors, 0);
+
+    let file = root.path().join("hooks.hpk");
+    let mut options = hpk::CreateOptions::new();
+    options.with_extensions(vec![]);
+    hpk::create(&options, &input, &file).unwrap();
+    let archive = fs::read(&file).unwrap();
+
+    // an existing archive is only replaced by a complete archive,
+    // cancelled while compressing the whole archive
+    let mut options = hpk::CreateOptions::new();
+    options.compress();
+    options.set_hooks(Cancel {
+        events: Rc::new(RefCell::new(Events::default())),
+        after_bytes: None,
+        after_files: Some(3),
+    });
+    let err = hpk::create(&options, &input, &file).unwrap_err();
+    assert!(std::matches!(err, hpk::HpkError::Cancelled));
+    assert_eq!(fs::read(&file).unwrap(), archive);
+    let leftovers = fs::read_dir(root.path()).unwrap().count();
+    assert_eq!(leftovers, 2, "only the input dir and the archive");
+
+    // cancelled while writing the second file
+    let dest = root.path().join("extracted");
+    let events = Rc::new(RefCell::new(Events::default()));
+    let mut options = hpk::ExtractOptions::new();
+    options.set_hooks(Cancel {
+        events: Rc::clone(&events),
+        after_bytes: Some(1000),
+        after_files: None,
+    });
+    let err = hpk::extract(&options, &file, &dest).unwrap_err();
+    assert!(std::matches!(err, hpk::HpkError::Cancelled));
+    assert!(dest.join("a.xml").exists());
+    assert!(!dest.join("b.bin").exists());
+    assert_eq!(events.borrow().done, 1);
+
+    // errors are reported to the hooks
+    let events = Rc::new(RefCell::new(Events::default()));
+    let mut options = hpk::ExtractOptions::new();
+    options.set_hooks(Cancel {
+        events: Rc::clone(&events),
+        after_bytes: None,
+        after_files: None,
+    });
+    let missing = root.path().join("missing.hpk");
+    assert!(hpk::extract(&options, &missing, &dest).is_err());
+    assert_eq!(events.borrow().errors, 1);
+}
+
+#[test]
+fn compress_and_decompress_file() {
+    use std::cell::RefCell;
+    use std::ops::ControlFlow;
+
+    let root = temp_root();
+    let files = [
+        ("a.lua", "print('Hello World')\n".repeat(500).into_bytes()),
+        ("b.bin", vec![7; 100_000]),
+    ];
+    let input = write_files(root.path(), "standalone", &files);
+
+    let plain = root.path().join("plain.hpk");
+    hpk::create(&hpk::CreateOptions::new(), &input, &plain).unwrap();
+    let archive = fs::read(&plain).unwrap();
+
+    // same output as a compressed archive written by `create`
+    let compressed = root.path().join("compressed.hpk");
+    hpk::compress_file(&hpk::CompressOptions::default(), &plain, &compressed).unwrap();
+    let mut options = hpk::CreateOptions::new();
+    options.compress();
+    let created = root.path().join("created.hpk");
+    hpk::create(&options, &input, &created).unwrap();
+    assert_eq!(fs::read(&compressed).unwrap(), fs::read(&created).unwrap());
+
+    // compressed in place
+    let mut options = hpk::CompressOptions::default();
+    options.use_lz4();
+    options.with_chunk_size(4096);
+    let len = hpk::compress_file(&options, &plain, &plain).unwrap();
+    assert_eq!(len, plain.metadata().unwrap().len());
+    assert!(hpk::walk(&plain).unwrap().is_compressed());
+    assert!(hpk::compress_file(&options, &plain, &plain).is_err());
+
+    let decompressed = root.path().join("decompressed.hpk");
+    let len = hpk::decompress_file(&plain, &decompressed).unwrap();
+    assert_eq!(len, archive.len() as u64);
+    assert_eq!(fs::read(&decompressed).unwrap(), archive);
+    assert!(hpk::decompress_file(&decompressed, &decompressed).is_err());
+
+    // the data of a single member
+    let member = root.path().join("b.bin.zstd");
+    let mut options = hpk::CompressOptions::default();
+    options.use_zstd();
+    hpk::compress_file(&options, &input.join("b.bin"), &member).unwrap();
+    let data = root.path().join("b.bin");
+    hpk::decompress_file(&member, &data).unwrap();
+    assert_eq!(fs::read(&data).unwrap(), vec![7; 100_000]);
+
+    // the read bytes are reported after every chunk
+    let reports = RefCell::new(vec![]);
+    let report = |read: u64| {
+        reports.borrow_mut().push(read);
+        ControlFlow::Continue(())
+    };
+    let mut options = hpk::CompressOptions::default();
+    options.with_chunk_size(65536);
+    let zlib = root.path().join("b.bin.zlib");
+    hpk::compress_file_with_progress(&options, &data, &zlib, &report).unwrap();
+    assert_eq!(*reports.borrow(), [65536, 100_000]);
+
+    // the same for the data compressed in memory
+    reports.borrow_mut().clear();
+    let buf = vec![7; 100_000];
+    let mut out = vec![];
+    hpk::compress_with_progress(&options, &mut &buf[..], &mut out, &report).unwrap();
+    assert_eq!(*reports.borrow(), [65536, 100_000]);
+    assert_eq!(out, fs::read(&zlib).unwrap());
+
+    // a cancelled compression keeps the existing output
+    let before = fs::read(&member).unwrap();
+    let cancel = |_: u64| ControlFlow::Break(());
+    let err = hpk::compress_file_with_progress(&options, &data, &member, &cancel);
+    assert!(std::matches!(err, Err(hpk::HpkError::Cancelled)));
+    assert_eq!(fs::read(&member).unwrap(), before);
+}
+
+#[test]
+fn overlay_load_order() {
+    let root = temp_root();
+    let mut options = hpk::CreateOptions::new();
+    options.with_default_filedates_format();
+    let base = [
+        ("Data/a.lua", "base"),
+        ("Data/b.xml", "base"),
+        ("c.txt", "base"),
+    ];
+    let dir = write_files(root.path(), "base", &base);
+    fs::create_dir(dir.join("Empty")).unwrap();
+    let old = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+    for (path, _) in &base {
+        filetime::set_file_times(dir.join(path), old, old).unwrap();
+    }
+    let base = root.path().join("base.hpk");
+    hpk::create(&options, &dir, &base).unwrap();
+    let mut options = hpk::CreateOptions::new();
+    options.compress();
+    let mod1 = [("Data/a.lua", "mod1"), ("Data/new.xml", "mod1")];
+    let mod1 = create_archive(root.path(), "mod1", &mod1, &options);
+    let loose = write_files(root.path(), "loose", &[("Data/b.xml", "loose")]);
+
+    let mut overlay = hpk::Overlay::new();
+    overlay.add_archive(&base).unwrap();
+    overlay.add_archive(&mod1).unwrap();
+    overlay.add_dir(&loose).unwrap();
+
+    let files: Vec<_> = overlay
+        .entries()
+        .map(|e| (e.path().to_path_buf(), e.layer(), e.overrides().to_vec()))
+        .collect();
+    assert_eq!(
+        files,
+        [
+            (PathBuf::from("Data/a.lua"), 1, vec![0]),
+            (PathBuf::from("Data/b.xml"), 2, vec![0]),
+            (PathBuf::from("Data/new.xml"), 1, vec![]),
+            (PathBuf::from("c.txt"), 0, vec![]),
+        ]
+    );
+    assert_eq!(overlay.layer_path(2), loose);
+    assert!(overlay.get("_filedates").is_none());
+
+    let read = |path: &str| {
+        let mut buf = vec![];
+        let entry = overlay.get(path).unwrap();
+        overlay.copy_file(entry, &mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    };
+    assert_eq!(read("Data/a.lua"), "mod1");
+    assert_eq!(read("Data/b.xml"), "loose");
+    assert_eq!(read("c.txt"), "base");
+
+    let dest = root.path().join("out");
+    overlay.extract(&hpk::ExtractOptions::new(), &dest).unwrap();
+    assert!(dest.join("Empty").is_dir());
+    assert!(!dest.join("_filedates").exists());
+    assert_eq!(fs::read_to_string(dest.join("Data/a.lua")).unwrap(), "mod1");
+    assert_eq!(
+        fs::read_to_string(dest.join("Data/b.xml")).unwrap(),
+        "loose"
+    );
+    // only the files of the base layer get the times of its _filedates
+    let mtime = |path: &str| {
+        let md = fs::metadata(dest.join(path)).unwrap();
+        filetime::FileTime::from_last_modification_time(&md)
+    };
+    assert_eq!(mtime("c.txt"), old);
+    assert_ne!(mtime("Data/a.lua"), old);
+    assert_ne!(mtime("Data/b.xml"), old);
+}
+
+#[test]
+fn merge_archives() {
+    let root = temp_root();
+    let mut options = hpk::CreateOptions::new();
+    options.with_default_filedates_format();
+    let base = [("Data/a.lua", "base"), ("c.txt", "base")];
+    let base = create_archive(root.path(), "base", &base, &options);
+    let mut options = hpk::CreateOptions::new();
+    options.with_short_filedates_format();
+    options.compress();
+    let mod1 = [("Data/a.lua", "mod1"), ("Data/new.xml", "mod1")];
+    let mod1 = create_archive(root.path(), "mod1", &mod1, &options);
+
+    let extract = |file: &Path| {
+        let dest = root.path().join("out");
+        let _ = fs::remove_dir_all(&dest);
+        let mut options = hpk::ExtractOptions::new();
+        options.skip_filedates();
+        hpk::extract(&options, file, &dest).unwrap();
+        let read = |path: &str| fs::read_to_string(dest.join(path)).unwrap();
+        let filedates = read("_filedates");
+        let filedates: Vec<_> = filedates
+            .lines()
+            .map(|l| l.split_once('=').unwrap())
+            .collect();
+        let lines: Vec<_> = filedates.iter().map(|(path, _)| path.to_string()).collect();
+        let short = filedates
+            .iter()
+            .all(|(_, v)| v.parse::<i64>().unwrap() < 100_000_000_000_000);
+        (read("Data/a.lua"), read("c.txt"), lines, short)
+    };
+
+    let merged = root.path().join("merged.hpk");
+    let options = hpk::MergeOptions::new();
+    hpk::merge(&options, &[&base, &mod1], &merged).unwrap();
+    let (a, c, lines, short) = extract(&merged);
+    assert_eq!((a.as_str(), c.as_str()), ("mod1", "base"));
+    assert_eq!(lines, ["Data", "c.txt", "Data/a.lua", "Data/new.xml"]);
+    assert!(!short);
+
+    // the compressed member is copied as it's stored
+    let member = |file: &Path, path: &str| {
+        let members = stored_members(file);
+        members.into_iter().find(|(p, _)| p == path).unwrap().1
+    };
+    let stored = member(&merged, "Data/a.lua");
+    assert_eq!(stored, member(&mod1, "Data/a.lua"));
+    assert!(stored.starts_with(b"ZLIB"));
+
+    let mut options = hpk::MergeOptions::new();
+    options.set_conflict(hpk::Conflict::FirstWins);
+    hpk::merge(&options, &[&base, &mod1], &merged).unwrap();
+    let (a, _, _, short) = extract(&merged);
+    assert_eq!(a, "base");
+    assert!(!short);
+
+    let mut options = hpk::MergeOptions::new();
+    options.set_conflict(hpk::Conflict::Error);
+    let err = hpk::merge(&options, &[&base, &mod1], &merged);
+    assert!(matches!(err, Err(hpk::HpkError::MergeConflict(p)) if p == Path::new("Data/a.lua")));
+
+    // the file of the later archive without a _filedates file drops the line of the
+    // overridden file, also for names that aren't utf-8
+    let mut options = hpk::CreateOptions::new();
+    options.set_name_encoding(hpk::NameEncoding::Cp1252);
+    options.with_default_filedates_format();
+    let base = [("Data/café.lua", "base"), ("c.txt", "base")];
+    let base = create_archive(root.path(), "base1252", &base, &options);
+    let mut options = hpk::CreateOptions::new();
+    options.set_name_encoding(hpk::NameEncoding::Cp1252);
+    let mod1 = create_archive(
+        root.path(),
+        "mod1252",
+        &[("Data/café.lua", "mod1")],
+        &options,
+    );
+    let mut options = hpk::MergeOptions::new();
+    options.set_name_encoding(hpk::NameEncoding::Cp1252);
+    hpk::merge(&options, &[&base, &mod1], &merged).unwrap();
+    let mut walk = hpk::walk(&merged).unwrap();
+    walk.set_name_encoding(hpk::NameEncoding::Cp1252);
+    let entries: Vec<_> = walk.by_ref().map(Result::unwrap).collect();
+    let entry = entries.iter().find(|e| e.path() == Path::new("_filedates"));
+    let mut filedates = vec![];
+    walk.read_file(entry.unwrap(), |mut r| {
+        r.read_to_end(&mut filedates)?;
+        Ok(())
+    })
+    .unwrap();
+    let lines: Vec<_> = filedates
+        .split(|b| *b == b'\n')
+        .filter_map(|l| l.split(|b| *b == b'=').next())
+        .filter(|p| !p.is_empty())
+        .collect();
+    assert_eq!(lines, [&b"Data"[..], b"c.txt"]);
+}
+
+#[test]
+fn split_archive() {
+    let root = temp_root();
+    let files = [
+        ("Data/a.dat", vec![b'x'; 3000]),
+        ("Data/b.dat", vec![b'x'; 5000]),
+        ("Maps/m.dat", vec![b'x'; 4000]),
+        ("readme.txt", vec![b'x'; 100]),
+    ];
+    let mut options = hpk::CreateOptions::new();
+    options.with_default_filedates_format();
+    let file = create_archive(root.path(), "pack", &files, &options);
+
+    let list = |file: &Path| {
+        let members = map_members(file, |_| Ok(()));
+        members
+            .into_iter()
+            .map(|(path, ())| path)
+            .collect::<Vec<_>>()
+    };
+
+    let dest = root.path().join("by-dir");
+    let parts = hpk::split(&hpk::SplitOptions::new(), &file, &dest).unwrap();
+    let names: Vec<_> = parts.iter().map(|p| p.file_name().unwrap()).collect();
+    assert_eq!(names, ["Data.hpk", "Maps.hpk", "pack.hpk"]);
+    assert_eq!(list(&parts[0]), ["Data/a.dat", "Data/b.dat", "_filedates"]);
+    assert_eq!(list(&parts[2]), ["readme.txt", "_filedates"]);
+
+    let out = root.path().join("out");
+    let mut options = hpk::ExtractOptions::new();
+    options.skip_filedates();
+    hpk::extract(&options, &parts[1], &out).unwrap();
+    let filedates = fs::read_to_string(out.join("_filedates")).unwrap();
+    let lines: Vec<_> = filedates
+        .lines()
+        .map(|l| l.split('=').next().unwrap())
+        .collect();
+    assert_eq!(lines, ["Maps/m.dat", "Maps"]);
+
+    let dest = root.path().join("by-size");
+    let mut options = hpk::SplitOptions::new();
+    options.with_max_size(6000);
+    let parts = hpk::split(&options, &file, &dest).unwrap();
+    assert_eq!(parts.len(), 3);
+    let mut paths = vec![];
+    for part in &parts {
+        assert!(fs::metadata(part).unwrap().len() <= 6000);
+        paths.extend(list(part).into_iter().filter(|p| p != "_filedates"));
+    }
+    assert_eq!(
+        paths,
+        ["Data/a.dat", "Data/b.dat", "Maps/m.dat", "readme.txt"]
+    );
+
+    // the lines are assigned to the entries with the encoding of the names
+    let mut options = hpk::CreateOptions::new();
+    options.set_name_encoding(hpk::NameEncoding::Cp1252);
+    options.with_default_filedates_format();
+    let file = create_archive(root.path(), "cp1252", &[("x/café.txt", "x")], &options);
+    let mut options = hpk::SplitOptions::new();
+    options.set_name_encoding(hpk::NameEncoding::Cp1252);
+    let parts = hpk::split(&options, &file, &root.path().join("by-dir-1252")).unwrap();
+    let out = root.path().join("out-1252");
+    let mut options = hpk::ExtractOptions::new();
+    options.set_name_encoding(hpk::NameEncoding::Cp1252);
+    options.skip_filedates();
+    hpk::extract(&options, &parts[0], &out).unwrap();
+    let filedates = fs::read(out.join("_filedates")).unwrap();
+    let lines: Vec<_> = filedates
+        .split(|b| *b == b'\n')
+        .filter_map(|l| l.split(|b| *b == b'=').next())
+        .filter(|p| !p.is_empty())
+        .collect();
+    assert_eq!(lines, [&b"x/caf\xE9.txt"[..], b"x"]);
+
+    // the files of the root directory can't be written to the archive of a directory
+    let files = [("conflict/a.txt", "a"), ("readme.txt", "readme")];
+    let file = create_archive(root.path(), "conflict", &files, &hpk::CreateOptions::new());
+    let dest = root.path().join("conflict-parts");
+    let err = hpk::split(&hpk::SplitOptions::new(), &file, &dest);
+    assert!(matches!(err, Err(hpk::HpkError::SplitConflict(p)) if p == Path::new("conflict.hpk")));
+}
+
+#[test]
+fn copy_members_verbatim() {
+    let root = temp_root();
+    let content = "print('hello')\n".repeat(100);
+    let mut options = hpk::CreateOptions::new();
+    options.use_lz4();
+    let src = [("Data/a.lua", content.as_str()), ("Data/b.txt", "src")];
+    let src = create_archive(root.path(), "src", &src, &options);
+    let dst = [("Data/a.lua", "dst"), ("c.txt", "dst")];
+    let dst = create_archive(root.path(), "dst", &dst, &hpk::CreateOptions::new());
+
+    let mut options = hpk::CopyOptions::new();
+    options.set_paths(&[glob::Pattern::new("Data/*.lua").unwrap()]);
+    let copied = hpk::copy_members(&options, &src, &dst).unwrap();
+    assert_eq!(copied, 1);
+
+    let src_members = stored_members(&src);
+    let dst_members = stored_members(&dst);
+    let paths: Vec<_> = dst_members.iter().map(|(p, _)| p.as_str()).collect();
+    assert_eq!(paths, ["Data/a.lua", "c.txt"]);
+    assert_eq!(dst_members[0], src_members[0]);
+    assert!(dst_members[0].1.starts_with(b"LZ4 "));
+
+    let out = root.path().join("out");
+    hpk::extract(&hpk::ExtractOptions::new(), &dst, &out).unwrap();
+    assert_eq!(fs::read_to_string(out.join("Data/a.lua")).unwrap(), content);
+    assert_eq!(fs::read_to_string(out.join("c.txt")).unwrap(), "dst");
+
+    // a file can't replace a directory of the destination
+    let file = [("Data", "file")];
+    let file = create_archive(root.path(), "file", &file, &hpk::CreateOptions::new());
+    let before = fs::read(&dst).unwrap();
+    let err = hpk::copy_members(&hpk::CopyOptions::new(), &file, &dst);
+    assert!(matches!(err, Err(hpk::HpkError::MergeConflict(p)) if p == Path::new("Data")));
+    assert_eq!(fs::read(&dst).unwrap(), before);
+}
+
+#[test]
+fn recompress_archive() {
+    let root = temp_root();
+    let content = "print('hello')\n".repeat(100);
+    let files = [("Data/a.lua", content.as_str()), ("b.txt", "text")];
+    let mut options = hpk::CreateOptions::new();
+    options.with_default_filedates_format();
+    let file = create_archive(root.path(), "pack", &files, &options);
+
+    let codecs = |file: &Path| map_members(file, |r| hpk::get_compression(r));
+
+    let lz4 = root.path().join("lz4.hpk");
+    let mut options = hpk::RecompressOptions::new();
+    options.set_codec(hpk::Compression::Lz4);
+    hpk::recompress(&options, &file, &lz4).unwrap();
+    assert_eq!(
+        codecs(&lz4),
+        [
+            (String::from("Data/a.lua"), hpk::Compression::Lz4),
+            (String::from("b.txt"), hpk::Compression::None),
+            (String::from("_filedates"), hpk::Compression::None),
+        ]
+    );
+
+    let out = root.path().join("out");
+    hpk::extract(&hpk::ExtractOptions::new(), &lz4, &out).unwrap();
+    assert_eq!(fs::read_to_string(out.join("Data/a.lua")).unwrap(), content);
+
+    let zlib = root.path().join("zlib.hpk");
+    let mut options = hpk::RecompressOptions::new();
+    options.set_codec(hpk::Compression::Zlib);
+    hpk::recompress(&options, &lz4, &zlib).unwrap();
+    assert_eq!(fs::read(&zlib).unwrap(), fs::read(&file).unwrap());
+
+    // the compression header doesn't match the size of the decompressed data
+    let data = fs::read(&lz4).unwrap();
+    let pos = data.windows(4).position(|w| w == b"LZ4 ").unwrap() + 4;
+    for inflated_length in [content.len() - 1, content.len() + 1] {
+        let mut data = data.clone();
+        data[pos..pos + 4].copy_from_slice(&(inflated_length as u32).to_le_bytes());
+        let corrupt = root.path().join("corrupt.hpk");
+        fs::write(&corrupt, data).unwrap();
+        let err = hpk::recompress(&options, &corrupt, &zlib);
+        assert!(matches!(err, Err(hpk::HpkError::InvalidCompressionHeader)));
+    }
+
+    let mut options = hpk::RecompressOptions::new();
+    options.set_codec(hpk::Compression::Zstd);
+    options.with_extensions(vec![String::from("txt")]);
+    hpk::recompress(&options, &file, &zlib).unwrap();
+    let codecs: Vec<_> = codecs(&zlib).into_iter().map(|(_, c)| c).collect();
+    assert_eq!(
+        codecs,
+        [
+            hpk::Compression::None,
+            hpk::Compression::Zstd,
+            hpk::Compression::None
+        ]
+    );
+
+    // an archive compressed as a whole
+    let mut options = hpk::CreateOptions::new();
+    options.with_default_filedates_format();
+    options.compress();
+    options.with_chunk_size(256);
+    let whole = create_archive(root.path(), "whole", &files, &options);
+    let zstd = root.path().join("zstd.hpk");
+    let mut options = hpk::RecompressOptions::new();
+    options.set_codec(hpk::Compression::Zstd);
+    hpk::recompress(&options, &whole, &zstd).unwrap();
+    let info = hpk::info(&zstd).unwrap();
+    assert_eq!(info.compression, hpk::Compression::Zstd);
+    assert_eq!(info.zstd_files, 1);
+
+    let mut options = hpk::RecompressOptions::new();
+    options.set_codec(hpk::Compression::Zlib);
+    options.with_chunk_size(256);
+    hpk::recompress(&options, &zstd, &zlib).unwrap();
+    assert_eq!(fs::read(&zlib).unwrap(), fs::read(&whole).unwrap());
+}